@@ -28,6 +28,7 @@ use libre_pvz::plant::peashooter::PeashooterPlugin;
 // use libre_pvz::scene::almanac::AlmanacPlugin;
 use libre_pvz::scene::lawn::LawnPlugin;
 use libre_pvz::resources::ResourcesPlugins;
+use libre_pvz::resources::pak::PakAssetPlugin;
 use libre_pvz::scene::loading::AssetState;
 use libre_pvz::seed_bank::SeedBankPlugin;
 
@@ -37,7 +38,12 @@ fn main() {
     //     Some(path) => PathBuf::from(path).into(),
     // };
 
-    App::new()
+    let mut app = App::new();
+    // serve assets from the original game package (must come before the asset plugin)
+    if let Some(pak) = std::env::var_os("LIBRE_PVZ_PAK") {
+        app.add_plugins(PakAssetPlugin::new(pak));
+    }
+    app
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(LawnPlugin::window()),
             // primary_window: Some(AlmanacPlugin::window()),
@@ -23,7 +23,6 @@ use std::fmt::{Debug, Display, Formatter};
 use std::path::PathBuf;
 use bevy::prelude::*;
 use bevy::asset::{Handle, AssetPath, LoadContext};
-use bevy::text::Font;
 use bevy::utils::HashMap;
 use bevy::sprite::Anchor;
 use bincode::{Encode, Decode};
@@ -35,6 +34,7 @@ use libre_pvz_animation::curve::Segment;
 use libre_pvz_animation::transform::{SpriteBundle2D, Transform2D, SpatialBundle2D};
 use crate::asset_ext;
 use crate::cached::{Cached, EntryWithKey, SortedSlice};
+use crate::font::{BitmapFont, BitmapText};
 use crate::loader::{AddTwoStageAsset, AssetExtensions, TwoStageAsset};
 
 /// Resources plugin.
@@ -50,6 +50,7 @@ impl Plugin for AnimationPlugin {
 /// Animations, originally in `.reanim` format.
 #[derive(Debug, Encode, Decode)]
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct AnimDesc {
     /// Frames per second.
     pub fps: f32,
@@ -71,6 +72,17 @@ impl AnimDesc {
             })
     }
 
+    /// Get an iterator of all the font file names in this animation.
+    pub fn font_files(&self) -> impl Iterator<Item = &Cached<PathBuf, Handle<BitmapFont>>> {
+        self.tracks.iter()
+            .flat_map(|track| track.frames.iter())
+            .flat_map(|frame| frame.0.iter())
+            .filter_map(|trans| match trans {
+                Action::LoadElement(Element::Text { font, .. }) => Some(font),
+                _ => None,
+            })
+    }
+
     /// Get a meta track by name.
     pub fn get_meta(&self, name: &str) -> Option<(usize, &Meta)> {
         let k = self.meta.binary_search_by_key(&name, |meta| meta.name.as_str()).ok()?;
@@ -81,6 +93,7 @@ impl AnimDesc {
 /// Meta data for animations.
 #[derive(Debug, Encode, Decode)]
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Meta {
     /// Name of this meta data.
     pub name: String,
@@ -90,6 +103,19 @@ pub struct Meta {
     pub end_frame: u16,
 }
 
+/// Separator between the track name and the range index in meta names, for meta tracks with
+/// several (discontinuous) visible ranges, e.g., `anim_blink#0` and `anim_blink#1`.
+pub const META_RANGE_SEPARATOR: char = '#';
+
+impl Meta {
+    /// Split the name into the meta track name and the range index (if any).
+    pub fn split_name(&self) -> (&str, Option<usize>) {
+        self.name.rsplit_once(META_RANGE_SEPARATOR)
+            .and_then(|(name, k)| Some((name, Some(k.parse().ok()?))))
+            .unwrap_or((&self.name, None))
+    }
+}
+
 impl EntryWithKey for Meta {
     type Key = str;
     fn key(&self) -> &str { &self.name }
@@ -98,6 +124,7 @@ impl EntryWithKey for Meta {
 /// A series of frames to play consecutively.
 #[derive(Debug, Encode, Decode)]
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Track {
     /// Track name for internal recognition.
     pub name: String,
@@ -105,10 +132,20 @@ pub struct Track {
     pub frames: Box<[Frame]>,
 }
 
+impl Track {
+    /// Does this track contain any text elements?
+    pub fn has_text(&self) -> bool {
+        self.frames.iter()
+            .flat_map(|frame| frame.0.iter())
+            .any(|action| matches!(action, Action::LoadElement(Element::Text { .. })))
+    }
+}
+
 /// Key frame: show and transform elements.
 /// Transformations are applied sequentially in one frame.
 #[derive(Debug, Encode, Decode)]
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Frame(pub Box<[Action]>);
 
 /// 2D vectors.
@@ -117,6 +154,7 @@ pub type RawVec2 = [f32; 2];
 /// Key frame action.
 #[derive(Debug, Clone, Encode, Decode)]
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "snake_case")]
 pub enum Action {
     /// Load an element to replace the current one on the stage.
@@ -155,6 +193,7 @@ pub mod action {
 /// Element on the stage. Only one element is allowed on a single frame.
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Encode, Decode)]
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Element {
     /// Text element.
@@ -162,7 +201,7 @@ pub enum Element {
         /// Text content to display. Characters not in the font are simply ignored.
         text: String,
         /// Font name.
-        font: Cached<PathBuf, Handle<Font>>,
+        font: Cached<PathBuf, Handle<BitmapFont>>,
     },
     /// Image element.
     Image {
@@ -242,6 +281,9 @@ impl Animation {
             bundle.sprite.anchor = Anchor::TopLeft;
             bundle.transform.z_order = z as f32 * 0.1;
             let this = commands.spawn((bundle, this)).id();
+            if track.has_text() {
+                commands.entity(this).insert(BitmapText::default());
+            }
             commands.entity(parent).add_child(this);
             call_back(z, &track.name, this);
         }
@@ -270,10 +312,14 @@ impl Animation {
         for act in frame.into_iter() {
             type _Image = _Identity<Handle<Image>>;
             type _IsVisible = _Identity<Visibility>;
+            type _Text = _Identity<BitmapText>;
             let vis = |vis| if vis { Visibility::Inherited } else { Visibility::Hidden };
             use Action::*;
             match act {
-                LoadElement(Element::Text { .. }) => todo!(),
+                LoadElement(Element::Text { text, font }) => {
+                    let font = font.cached.get().unwrap().clone();
+                    builder.push_keyframe(_Text::default(), k, BitmapText { text: text.clone(), font })
+                }
                 LoadElement(Element::Image { image }) => {
                     let image = image.cached.get().unwrap().clone();
                     builder.push_keyframe(_Image::default(), k, image)
@@ -309,9 +355,12 @@ impl TwoStageAsset for Animation {
     type Repr = AnimDesc;
     const EXTENSIONS: AssetExtensions = asset_ext!("anim");
     fn post_process(anim: AnimDesc, load_context: &mut LoadContext) -> anyhow::Result<(Animation, Vec<AssetPath<'static>>)> {
-        let deps = anim.image_files().collect::<Vec<_>>();
-        let mut dep_paths = Vec::with_capacity(deps.len());
-        for name in deps {
+        let mut dep_paths = Vec::new();
+        for name in anim.image_files() {
+            name.init_handle(load_context);
+            dep_paths.push(name.asset_path().into_owned());
+        }
+        for name in anim.font_files() {
             name.init_handle(load_context);
             dep_paths.push(name.asset_path().into_owned());
         }
@@ -115,6 +115,17 @@ impl<K, I> From<K> for Cached<K, I> {
     fn from(raw_key: K) -> Self { Cached { raw_key, cached: OnceCell::new() } }
 }
 
+/// Schema of the raw key, consistent with the transparent serialization.
+#[cfg(feature = "schema")]
+impl<K: schemars::JsonSchema, I> schemars::JsonSchema for Cached<K, I> {
+    fn is_referenceable() -> bool { false }
+    fn schema_name() -> String { K::schema_name() }
+    fn schema_id() -> std::borrow::Cow<'static, str> { K::schema_id() }
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        K::json_schema(gen)
+    }
+}
+
 impl<K: Encode, I> Encode for Cached<K, I> {
     fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
         self.raw_key.encode(encoder)
@@ -160,6 +171,7 @@ pub trait ContainerWithKey {
 /// Raw key storage with cached handle.
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
 #[derive(Serialize, Encode)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(transparent)]
 pub struct SortedSlice<T>(Box<[T]>);
 
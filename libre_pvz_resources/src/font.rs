@@ -0,0 +1,297 @@
+/*
+ * librePvZ-resources: resource loading logics for librePvZ.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Bitmap fonts and text rendering.
+
+use std::path::PathBuf;
+use bevy::prelude::*;
+use bevy::asset::{AssetPath, LoadContext};
+use bevy::sprite::Anchor;
+use bevy::transform::TransformSystem;
+use bevy::utils::HashSet;
+use bincode::{Encode, Decode};
+use serde::{Serialize, Deserialize};
+use libre_pvz_animation::{AnimationExt, AnimationSystem};
+use libre_pvz_animation::curve::animatable::Animatable;
+use libre_pvz_animation::transform::{SpriteBundle2D, Transform2D};
+use crate::asset_ext;
+use crate::cached::{Cached, ContainerWithKey, EntryWithKey, SortedSlice};
+use crate::loader::{AddTwoStageAsset, AssetExtensions, TwoStageAsset};
+
+/// Bitmap font plugin.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct FontPlugin;
+
+impl Plugin for FontPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_two_stage_asset::<BitmapFont>()
+            .register_for_animation::<BitmapText>()
+            .add_systems(PostUpdate, (layout_text_system, text_alpha_system)
+                .after(AnimationSystem::PlayerSampling)
+                .before(TransformSystem::TransformPropagate));
+    }
+}
+
+/// Bitmap fonts, originally font description scripts (`data/*.txt`) and their images.
+#[derive(Debug, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FontDesc {
+    /// Characters mapped to others before looking up the glyphs, e.g., lowercase letters for
+    /// fonts with only uppercase ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub char_map: Vec<(char, char)>,
+    /// Layers, drawn in order, e.g., a shadow and then the glyphs.
+    pub layers: Box<[FontLayer]>,
+}
+
+/// A layer in a bitmap font, with its own image and glyph metrics.
+#[derive(Debug, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FontLayer {
+    /// Image containing the glyphs.
+    pub image: Cached<PathBuf, Handle<Image>>,
+    /// Distance from the top of a line to the baseline.
+    pub ascent: i32,
+    /// Distance between the tops of consecutive lines.
+    pub line_spacing: i32,
+    /// Extra space between consecutive characters.
+    #[serde(default)]
+    pub spacing: i32,
+    /// Offset of the whole layer.
+    #[serde(default)]
+    pub offset: [i32; 2],
+    /// Colour multiplier (RGBA, with components in `[0, 1]`).
+    #[serde(default = "defaults::white")]
+    pub color: [f32; 4],
+    /// Glyphs in this layer.
+    pub glyphs: SortedSlice<Glyph>,
+    /// Kerning pairs in this layer.
+    #[serde(default)]
+    pub kerning: SortedSlice<KerningPair>,
+}
+
+mod defaults {
+    pub const fn white() -> [f32; 4] { [1.0; 4] }
+}
+
+impl FontLayer {
+    /// Get the glyph for some character.
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        Some(self.glyphs.get_by_handle(self.glyphs.get_by_key(&c)?))
+    }
+
+    /// Get the kerning offset between two consecutive characters.
+    pub fn kerning(&self, pair: [char; 2]) -> i32 {
+        self.kerning.get_by_key(&pair).map_or(0, |k| self.kerning[k].offset)
+    }
+}
+
+/// Metrics of a single character.
+#[derive(Debug, Copy, Clone, PartialEq, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Glyph {
+    /// The character.
+    pub char: char,
+    /// Rectangle (`[x, y, width, height]`) of this glyph in the layer image.
+    pub rect: [i32; 4],
+    /// Offset of the glyph image relative to the pen position.
+    pub offset: [i32; 2],
+    /// Horizontal advance of the pen position after this glyph.
+    pub width: i32,
+}
+
+impl EntryWithKey for Glyph {
+    type Key = char;
+    fn key(&self) -> &char { &self.char }
+}
+
+/// Adjustment to the advance between two consecutive characters.
+#[derive(Debug, Copy, Clone, PartialEq, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct KerningPair {
+    /// The two characters.
+    pub pair: [char; 2],
+    /// Offset added to the advance of the first character.
+    pub offset: i32,
+}
+
+impl EntryWithKey for KerningPair {
+    type Key = [char; 2];
+    fn key(&self) -> &[char; 2] { &self.pair }
+}
+
+/// A glyph placed by [`FontDesc::layout`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlacedGlyph {
+    /// Index of the layer.
+    pub layer: usize,
+    /// Rectangle of this glyph in the layer image.
+    pub rect: Rect,
+    /// Position of the top left corner, relative to the top left corner of the text, in pixels
+    /// (with the y-axis pointing downwards).
+    pub position: Vec2,
+}
+
+impl FontDesc {
+    /// Map a character using [`char_map`](FontDesc::char_map).
+    pub fn map_char(&self, c: char) -> char {
+        self.char_map.iter().find(|(from, _)| *from == c).map_or(c, |&(_, to)| to)
+    }
+
+    /// Lay out some text: glyphs for all the layers, in drawing order. Characters not in the font
+    /// are simply ignored, and `'\n'` starts a new line.
+    pub fn layout(&self, text: &str) -> Vec<PlacedGlyph> {
+        let mut placed = Vec::new();
+        for (k, layer) in self.layers.iter().enumerate() {
+            let [mut x, mut y] = [0, 0];
+            let mut last = None;
+            for c in text.chars().map(|c| self.map_char(c)) {
+                if c == '\n' {
+                    [x, y] = [0, y + layer.line_spacing];
+                    last = None;
+                    continue;
+                }
+                let Some(glyph) = layer.glyph(c) else { continue };
+                if let Some(last) = last { x += layer.kerning([last, c]); }
+                let [u, v, w, h] = glyph.rect;
+                if w > 0 && h > 0 {
+                    placed.push(PlacedGlyph {
+                        layer: k,
+                        rect: Rect::new(u as f32, v as f32, (u + w) as f32, (v + h) as f32),
+                        position: Vec2::new(
+                            (x + layer.offset[0] + glyph.offset[0]) as f32,
+                            (y + layer.offset[1] + glyph.offset[1]) as f32,
+                        ),
+                    });
+                }
+                x += glyph.width + layer.spacing;
+                last = Some(c);
+            }
+        }
+        placed
+    }
+}
+
+/// Bitmap font and its images.
+#[derive(Asset, TypePath)]
+#[allow(missing_debug_implementations)]
+pub struct BitmapFont {
+    /// The font description.
+    pub description: FontDesc,
+}
+
+impl TwoStageAsset for BitmapFont {
+    type Repr = FontDesc;
+    const EXTENSIONS: AssetExtensions = asset_ext!("font");
+    fn post_process(font: FontDesc, load_context: &mut LoadContext) -> anyhow::Result<(BitmapFont, Vec<AssetPath<'static>>)> {
+        let mut dep_paths = Vec::with_capacity(font.layers.len());
+        for layer in font.layers.iter() {
+            layer.image.init_handle(load_context);
+            dep_paths.push(layer.image.asset_path().into_owned());
+        }
+        Ok((BitmapFont { description: font }, dep_paths))
+    }
+}
+
+/// Text rendered with a [`BitmapFont`]. Glyphs are spawned as sprite children, with the top left
+/// corner of the text at the origin, and with the alpha of the [`Sprite`] on this entity (if any).
+#[derive(Component, Debug, Default, Clone, PartialEq)]
+pub struct BitmapText {
+    /// Text content to display.
+    pub text: String,
+    /// The font.
+    pub font: Handle<BitmapFont>,
+}
+
+impl Animatable for BitmapText {
+    fn interpolate(a: &BitmapText, _b: &BitmapText, _time: f32) -> BitmapText { a.clone() }
+}
+
+/// Glyph sprites spawned for [`BitmapText`]s, with the colour of their layers.
+#[derive(Component, Debug, Copy, Clone)]
+pub struct TextGlyph {
+    /// Colour of the layer, before applying the alpha of the text.
+    pub color: Color,
+}
+
+impl TextGlyph {
+    fn color_with_alpha(self, alpha: f32) -> Color {
+        self.color.with_alpha(self.color.alpha() * alpha)
+    }
+}
+
+/// Lay out texts when they change, or when their fonts are (re)loaded.
+#[allow(clippy::type_complexity)]
+fn layout_text_system(
+    mut events: EventReader<AssetEvent<BitmapFont>>,
+    texts: Query<(Entity, Ref<BitmapText>, Option<&Sprite>, Option<&Children>)>,
+    glyphs: Query<(), With<TextGlyph>>,
+    fonts: Res<Assets<BitmapFont>>,
+    mut commands: Commands,
+) {
+    let loaded = events.read().filter_map(|event| match *event {
+        AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => Some(id),
+        _ => None,
+    }).collect::<HashSet<_>>();
+    for (entity, text, sprite, children) in texts.iter() {
+        if !text.is_changed() && !loaded.contains(&text.font.id()) { continue; }
+        for &child in children.into_iter().flatten() {
+            if glyphs.contains(child) { commands.entity(child).despawn_recursive(); }
+        }
+        let Some(font) = fonts.get(&text.font) else { continue };
+        let alpha = sprite.map_or(1.0, |sprite| sprite.color.alpha());
+        for placed in font.description.layout(&text.text) {
+            let layer = &font.description.layers[placed.layer];
+            let [r, g, b, a] = layer.color;
+            let glyph = TextGlyph { color: Color::srgba(r, g, b, a) };
+            let mut bundle = SpriteBundle2D {
+                texture: layer.image.cached.get().unwrap().clone(),
+                ..SpriteBundle2D::default()
+            };
+            bundle.sprite.color = glyph.color_with_alpha(alpha);
+            bundle.sprite.rect = Some(placed.rect);
+            bundle.sprite.anchor = Anchor::TopLeft;
+            bundle.transform = Transform2D {
+                translation: Vec2::new(placed.position.x, -placed.position.y),
+                z_order: placed.layer as f32 * 0.001,
+                ..Transform2D::IDENTITY
+            };
+            let glyph = commands.spawn((bundle, glyph)).id();
+            commands.entity(entity).add_child(glyph);
+        }
+    }
+}
+
+/// Apply the alpha of texts to their glyphs.
+#[allow(clippy::type_complexity)]
+fn text_alpha_system(
+    texts: Query<(&Sprite, &Children), (With<BitmapText>, Changed<Sprite>)>,
+    mut glyphs: Query<(&mut Sprite, &TextGlyph), Without<BitmapText>>,
+) {
+    for (sprite, children) in texts.iter() {
+        let alpha = sprite.color.alpha();
+        for &child in children.iter() {
+            let Ok((mut glyph_sprite, glyph)) = glyphs.get_mut(child) else { continue };
+            glyph_sprite.color = glyph.color_with_alpha(alpha);
+        }
+    }
+}
@@ -25,10 +25,16 @@
 pub mod dynamic;
 pub mod cached;
 pub mod loader;
+pub mod pak;
 
 // contents
 pub mod animation;
+pub mod font;
+pub mod masked_image;
 pub mod model;
+pub mod param;
+pub mod strings;
+pub mod trail;
 
 pub use once_cell;
 
@@ -36,7 +42,11 @@ use bevy::prelude::*;
 use bevy::app::PluginGroupBuilder;
 
 use animation::AnimationPlugin;
+use font::FontPlugin;
+use masked_image::MaskedImagePlugin;
 use model::ModelPlugin;
+use strings::StringsPlugin;
+use trail::TrailPlugin;
 
 /// Resources plugin group.
 #[derive(Default, Debug, Copy, Clone)]
@@ -46,6 +56,10 @@ impl PluginGroup for ResourcesPlugins {
     fn build(self) -> PluginGroupBuilder {
         PluginGroupBuilder::start::<ResourcesPlugins>()
             .add(AnimationPlugin)
+            .add(FontPlugin)
+            .add(MaskedImagePlugin)
             .add(ModelPlugin)
+            .add(StringsPlugin)
+            .add(TrailPlugin)
     }
 }
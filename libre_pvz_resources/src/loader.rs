@@ -20,16 +20,24 @@
 //! structures, and therefore require customisation over bevy's [`AssetLoader`]. We do so by
 //! requiring an additional "secondary extension" in asset file names. For example, a file named
 //! "`Peashooter.anim.bin`" is treated as encoded in `bincode`, and has a resource type "`anim`".
+//!
+//! Files in `bincode` start with a [magic number](BIN_MAGIC) and a [`BinHeader`], recording the
+//! resource type and the layout version, so that outdated files are reported properly (or migrated
+//! by [`TwoStageAsset::migrate`]) instead of being silently mis-decoded.
 
+use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter};
+use std::io::{Cursor, Read, Write};
 use std::marker::PhantomData;
-use anyhow::{Error, Result};
+use anyhow::{Context, Error, Result};
+use async_channel::Receiver;
 use bevy::prelude::*;
 use bevy::asset::{Asset, AssetLoader, AssetPath, AsyncReadExt, LoadContext};
 use bevy::asset::io::Reader;
 use bevy::log::warn;
+use bevy::tasks::futures_lite::future;
 use bevy::utils::ConditionalSendFuture;
-use bincode::Decode;
+use bincode::{Decode, Encode};
 use derivative::Derivative;
 use serde::de::DeserializeOwned;
 
@@ -39,6 +47,8 @@ pub type StrList = &'static [&'static str];
 /// File extensions for two-stage assets. See also [`asset_ext`](crate::asset_ext).
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct AssetExtensions {
+    /// Resource type, i.e., the secondary extension, e.g., `"anim"`.
+    pub kind: &'static str,
     /// File extensions for YAML file storage, e.g., `["anim.yaml", "anim.yml"]`.
     pub yaml: StrList,
     /// File extensions for JSON file storage, e.g., `["anim.json"]`.
@@ -54,6 +64,7 @@ pub struct AssetExtensions {
 /// # use libre_pvz_resources::asset_ext;
 /// use libre_pvz_resources::loader::AssetExtensions;
 /// assert_eq!(asset_ext!("anim"), AssetExtensions {
+///     kind: "anim",
 ///     yaml: &["anim.yaml", "anim.yml"],
 ///     json: &["anim.json"],
 ///     bin: &["anim.bin"],
@@ -63,6 +74,7 @@ pub struct AssetExtensions {
 macro_rules! asset_ext {
     ($ext:literal) => {
         $crate::loader::AssetExtensions {
+            kind: $ext,
             yaml: &[::std::concat!($ext, ".yaml"), ::std::concat!($ext, ".yml")],
             json: &[::std::concat!($ext, ".json")],
             bin: &[::std::concat!($ext, ".bin")],
@@ -77,23 +89,171 @@ macro_rules! asset_ext {
 /// and share their post-processing logic.
 pub trait TwoStageAsset: Asset + Sized {
     /// The decoded representation for this asset.
-    type Repr: Decode + DeserializeOwned;
+    type Repr: Decode + DeserializeOwned + Send;
     /// The file extensions this asset is associated to.
     const EXTENSIONS: AssetExtensions;
     /// The post-processing logic: transform the `Repr` to a more compact in-memory form, require
     /// loading the dependencies and store their handles in the appropriate locations, and submit
     /// the resulting asset to the asset loader.
     fn post_process(repr: Self::Repr, load_context: &mut LoadContext) -> Result<(Self, Vec<AssetPath<'static>>)>;
+    /// Version of the `bincode` layout for `Repr`, recorded in the [`BinHeader`]. Bump this
+    /// whenever the layout changes, and handle the older versions in [`migrate`].
+    ///
+    /// [`migrate`]: TwoStageAsset::migrate
+    const BIN_VERSION: u32 = 1;
+    /// Decode `bincode` data (after the header) of an older layout `version`, and convert it to
+    /// the current `Repr`. See also [`decode_bin_payload`]. Older versions are rejected by default.
+    fn migrate(version: u32, _payload: &[u8]) -> Result<Self::Repr> {
+        anyhow::bail!(
+            "version {version} of '{}' assets is no longer supported (current version: {}), \
+             please regenerate the file",
+            Self::EXTENSIONS.kind, Self::BIN_VERSION,
+        )
+    }
 }
 
 const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
 
+/// Magic number at the very beginning of `bincode` files.
+pub const BIN_MAGIC: &str = "LPVZ";
+
+/// Header for `bincode` files, right after the [magic number](BIN_MAGIC).
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct BinHeader {
+    /// Resource type, see [`AssetExtensions::kind`].
+    pub kind: String,
+    /// Version of the `bincode` layout, see [`TwoStageAsset::BIN_VERSION`].
+    pub version: u32,
+}
+
+impl BinHeader {
+    /// Header for the current version of some asset.
+    pub fn of<T: TwoStageAsset>() -> BinHeader {
+        BinHeader { kind: T::EXTENSIONS.kind.to_string(), version: T::BIN_VERSION }
+    }
+}
+
+/// Encode some data in `bincode`, with the magic number and the header.
+pub fn encode_bin_with<T: Encode, W: Write>(header: &BinHeader, data: &T, output: &mut W) -> Result<()> {
+    output.write_all(BIN_MAGIC.as_bytes())?;
+    bincode::encode_into_std_write(header, output, BINCODE_CONFIG)?;
+    bincode::encode_into_std_write(data, output, BINCODE_CONFIG)?;
+    Ok(())
+}
+
+/// Encode an asset in `bincode`, with the magic number and the header for its current version.
+pub fn encode_bin<T: TwoStageAsset, W: Write>(repr: &T::Repr, output: &mut W) -> Result<()>
+    where T::Repr: Encode {
+    encode_bin_with(&BinHeader::of::<T>(), repr, output)
+}
+
+/// Decode an asset in `bincode`, checking the magic number and the header. Older versions are
+/// converted using [`TwoStageAsset::migrate`].
+pub fn decode_bin<T: TwoStageAsset>(src: &[u8]) -> Result<T::Repr> {
+    decode_bin_from_std_read::<T, _>(src)
+}
+
+/// Decode an asset in `bincode` from a reader, see [`decode_bin`]. Only older versions, which
+/// are rare, are read into memory as a whole for [migration](TwoStageAsset::migrate).
+pub fn decode_bin_from_std_read<T: TwoStageAsset, R: Read>(mut src: R) -> Result<T::Repr> {
+    let kind = T::EXTENSIONS.kind;
+    let mut magic = [0; BIN_MAGIC.len()];
+    let has_magic = src.read_exact(&mut magic).is_ok() && magic == BIN_MAGIC.as_bytes();
+    anyhow::ensure!(
+        has_magic,
+        "missing magic number '{BIN_MAGIC}' for an asset of type '{kind}' \
+         (generated by an older version of reanim-decode?), please regenerate the file"
+    );
+    let header: BinHeader = bincode::decode_from_std_read(&mut src, BINCODE_CONFIG)
+        .with_context(|| format!("malformed header for an asset of type '{kind}'"))?;
+    if header.kind != kind {
+        anyhow::bail!("expecting an asset of type '{kind}', found '{}'", header.kind);
+    }
+    match header.version.cmp(&T::BIN_VERSION) {
+        Ordering::Equal => {
+            let content = bincode::decode_from_std_read(&mut src, BINCODE_CONFIG)?;
+            warn_trailing_bytes::<T::Repr>(std::io::copy(&mut src, &mut std::io::sink())?);
+            Ok(content)
+        }
+        Ordering::Less => {
+            let mut payload = Vec::new();
+            src.read_to_end(&mut payload)?;
+            T::migrate(header.version, &payload)
+        }
+        Ordering::Greater => anyhow::bail!(
+            "version {} of '{kind}' assets is newer than supported (current version: {})",
+            header.version, T::BIN_VERSION,
+        ),
+    }
+}
+
+/// Decode `bincode` data without any header, e.g., the payload after the [`BinHeader`].
+pub fn decode_bin_payload<T: Decode>(src: &[u8]) -> Result<T> {
+    let (content, n) = bincode::decode_from_slice(src, BINCODE_CONFIG)?;
+    warn_trailing_bytes::<T>((src.len() - n) as u64);
+    Ok(content)
+}
+
+fn warn_trailing_bytes<T>(k: u64) {
+    if k > 0 {
+        warn!("{k} trailing bytes ignored when loading {}", std::any::type_name::<T>())
+    }
+}
+
+/// Size of the chunks sent from [`AssetFormat::load_raw`] to the decoder.
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Number of chunks read ahead of the decoder, bounding the memory for buffering.
+const CHUNK_QUEUE_LEN: usize = 4;
+
+/// A blocking reader over the chunks received from a channel, until the sender is dropped.
+struct ChunkReader {
+    chunks: Receiver<Vec<u8>>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() { return Ok(n); }
+            match self.chunks.recv_blocking() {
+                Ok(chunk) => self.current = Cursor::new(chunk),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
 /// Frontend asset format.
 pub trait AssetFormat: Copy + Send + Sync + 'static {
     /// Get the extensions list.
     fn get_extension(self, extensions: AssetExtensions) -> StrList;
-    /// Load from raw bytes to intermediate representation.
-    fn load_raw<T: Decode + DeserializeOwned>(self, src: &[u8]) -> Result<T>;
+    /// Decode from a (blocking) reader to intermediate representation.
+    fn decode<T: TwoStageAsset, R: Read>(self, src: R) -> Result<T::Repr>;
+    /// Load from an asset reader to intermediate representation. The file is read asynchronously,
+    /// and sent in chunks to the decoder running on a separate thread, so that parsing large files
+    /// does not occupy the IO task pool, and only a few chunks are buffered at a time.
+    fn load_raw<T: TwoStageAsset>(self, reader: &mut Reader) -> impl ConditionalSendFuture<Output = Result<T::Repr>> {
+        async move {
+            let (sender, chunks) = async_channel::bounded(CHUNK_QUEUE_LEN);
+            let current = Cursor::new(Vec::new());
+            let decode = blocking::unblock(move || self.decode::<T, _>(ChunkReader { chunks, current }));
+            let read = async move {
+                let mut buffer = vec![0; CHUNK_SIZE];
+                loop {
+                    let n = reader.read(&mut buffer).await?;
+                    // the decoder drops the receiver when it finishes (or fails) early
+                    if n == 0 || sender.send(buffer[..n].to_vec()).await.is_err() { break; }
+                }
+                // mark the end of input ('future::zip' does not drop finished futures)
+                drop(sender);
+                Ok::<_, Error>(())
+            };
+            let (read, decoded) = future::zip(read, decode).await;
+            read?;
+            decoded
+        }
+    }
 }
 
 /// [JSON](serde_json) format.
@@ -102,8 +262,8 @@ pub struct Json;
 
 impl AssetFormat for Json {
     fn get_extension(self, extensions: AssetExtensions) -> StrList { extensions.json }
-    fn load_raw<T: Decode + DeserializeOwned>(self, src: &[u8]) -> Result<T> {
-        serde_json::from_slice(src).map_err(Error::from)
+    fn decode<T: TwoStageAsset, R: Read>(self, src: R) -> Result<T::Repr> {
+        serde_json::from_reader(src).map_err(Error::from)
     }
 }
 
@@ -113,8 +273,9 @@ pub struct Yaml;
 
 impl AssetFormat for Yaml {
     fn get_extension(self, extensions: AssetExtensions) -> StrList { extensions.yaml }
-    fn load_raw<T: Decode + DeserializeOwned>(self, src: &[u8]) -> Result<T> {
-        serde_yaml::from_slice(src).map_err(Error::from)
+    fn decode<T: TwoStageAsset, R: Read>(self, src: R) -> Result<T::Repr> {
+        // TODO: 'serde_yaml' reads the whole input into memory before parsing
+        serde_yaml::from_reader(src).map_err(Error::from)
     }
 }
 
@@ -124,13 +285,8 @@ pub struct Bincode;
 
 impl AssetFormat for Bincode {
     fn get_extension(self, extensions: AssetExtensions) -> StrList { extensions.bin }
-    fn load_raw<T: Decode + DeserializeOwned>(self, src: &[u8]) -> Result<T> {
-        let (content, n) = bincode::decode_from_slice(src, BINCODE_CONFIG)?;
-        if n < src.len() {
-            let k = src.len() - n;
-            warn!("{k} trailing bytes ignored when loading {}", std::any::type_name::<T>())
-        }
-        Ok(content)
+    fn decode<T: TwoStageAsset, R: Read>(self, src: R) -> Result<T::Repr> {
+        decode_bin_from_std_read::<T, R>(src)
     }
 }
 
@@ -158,10 +314,7 @@ impl<T: TwoStageAsset, Fmt: AssetFormat> AssetLoader for TwoStageAssetLoader<T,
         load_context: &'a mut LoadContext,
     ) -> impl ConditionalSendFuture<Output = Result<Self::Asset>> {
         async move {
-            let mut bytes = Vec::new();
-            reader.read_to_end(&mut bytes).await?;
-            // TODO: redesign `AssetFormat::load_raw` to use async?
-            let raw = self.0.load_raw::<T::Repr>(&bytes)?;
+            let raw = self.0.load_raw::<T>(reader).await?;
             // TODO: check how dependencies are managed, redesign `TwoStageAsset::post_process`
             let (res, _) = T::post_process(raw, load_context)?;
             Ok(res)
@@ -0,0 +1,124 @@
+/*
+ * librePvZ-resources: resource loading logics for librePvZ.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! JPEG images with separate alpha masks.
+//!
+//! JPEG has no alpha channel, so the original game stores the transparency of such images in a
+//! sibling greyscale PNG, named either `_name.png` or `name_.png` for an image `name.jpg` (white
+//! for opaque, black for transparent). The [`MaskedImageLoader`] loads JPEG images, and merges the
+//! companion mask (if any) into the alpha channel, so that the result can be used as any other
+//! [`Image`], e.g., in animations or asset collections.
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Error, Result};
+use bevy::prelude::*;
+use bevy::asset::{AssetLoader, AssetPath, AsyncReadExt, LoadContext};
+use bevy::asset::io::{AssetReaderError, Reader};
+use bevy::asset::ReadAssetBytesError;
+use bevy::render::texture::ImageLoaderSettings;
+use bevy::utils::ConditionalSendFuture;
+use image::{DynamicImage, GrayImage, ImageFormat, RgbaImage};
+
+/// Plugin for loading JPEG images with their alpha masks.
+///
+/// This loader takes over the `jpg` and `jpeg` extensions, so bevy's `jpeg` feature should be
+/// left disabled; otherwise, bevy warns about duplicate loaders (ours is still preferred).
+#[derive(Default, Debug, Copy, Clone)]
+pub struct MaskedImagePlugin;
+
+impl Plugin for MaskedImagePlugin {
+    fn build(&self, _app: &mut App) {}
+    fn finish(&self, app: &mut App) {
+        // bevy's 'ImagePlugin' registers its loader in 'finish', and the last one registered wins
+        app.register_asset_loader(MaskedImageLoader);
+    }
+}
+
+/// Loader for JPEG images, with alpha masks merged in. Accepts bevy's [`ImageLoaderSettings`],
+/// except that the [`format`](ImageLoaderSettings::format) is ignored.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct MaskedImageLoader;
+
+impl AssetLoader for MaskedImageLoader {
+    type Asset = Image;
+    type Settings = ImageLoaderSettings;
+    type Error = Error;
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        settings: &'a ImageLoaderSettings,
+        load_context: &'a mut LoadContext,
+    ) -> impl ConditionalSendFuture<Output = Result<Image>> {
+        async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let mut image = image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)
+                .with_context(|| format!("failed to decode image {:?}", load_context.path()))?
+                .into_rgba8();
+            if let Some((path, mask)) = read_alpha_mask(load_context).await? {
+                let mask = image::load_from_memory_with_format(&mask, ImageFormat::Png)
+                    .with_context(|| format!("failed to decode alpha mask {path:?}"))?;
+                if let Err(err) = apply_alpha_mask(&mut image, &mask.into_luma8()) {
+                    warn!("alpha mask {path:?} ignored: {err}");
+                }
+            }
+            let mut image = Image::from_dynamic(
+                DynamicImage::ImageRgba8(image),
+                settings.is_srgb,
+                settings.asset_usage,
+            );
+            image.sampler = settings.sampler.clone();
+            Ok(image)
+        }
+    }
+    fn extensions(&self) -> &[&str] { &["jpg", "jpeg"] }
+}
+
+/// Candidate paths for the alpha mask of an image, in the order they are looked up.
+pub fn alpha_mask_paths(path: &Path) -> [PathBuf; 2] {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let dir = path.parent().unwrap_or(Path::new(""));
+    [dir.join(format!("_{stem}.png")), dir.join(format!("{stem}_.png"))]
+}
+
+/// Read the first alpha mask found for the asset being loaded (from the same asset source).
+async fn read_alpha_mask(load_context: &mut LoadContext<'_>) -> Result<Option<(PathBuf, Vec<u8>)>> {
+    let source = load_context.asset_path().source().clone_owned();
+    for path in alpha_mask_paths(load_context.path()) {
+        let asset_path = AssetPath::from_path(&path).with_source(source.clone());
+        match load_context.read_asset_bytes(asset_path).await {
+            Ok(bytes) => return Ok(Some((path, bytes))),
+            Err(ReadAssetBytesError::AssetReaderError(AssetReaderError::NotFound(_))) => {}
+            Err(err) => return Err(err).with_context(|| format!("failed to read alpha mask {path:?}")),
+        }
+    }
+    Ok(None)
+}
+
+/// Replace the alpha channel of an image with a greyscale mask of the same size.
+pub fn apply_alpha_mask(image: &mut RgbaImage, mask: &GrayImage) -> Result<()> {
+    anyhow::ensure!(
+        image.dimensions() == mask.dimensions(),
+        "size mismatch: image is {:?}, but mask is {:?}",
+        image.dimensions(), mask.dimensions(),
+    );
+    for (pixel, alpha) in image.pixels_mut().zip(mask.pixels()) {
+        pixel[3] = alpha[0];
+    }
+    Ok(())
+}
@@ -89,6 +89,7 @@ impl Plugin for ModelPlugin {
 
 /// Model: animation together with its association.
 #[derive(Debug, Encode, Decode, Serialize, Deserialize, Asset, TypePath)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Model {
     /// Animation, the all-in-one source.
     pub animation: Cached<PathBuf, Handle<Animation>>,
@@ -131,6 +132,7 @@ impl TwoStageAsset for Model {
 
 /// State controls the appearance and behaviours.
 #[derive(Debug, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct State {
     /// Name of this state.
     pub name: String,
@@ -143,8 +145,10 @@ pub struct State {
         skip_serializing_if = "defaults::is_zero_duration",
         with = "duration_from_secs"
     )]
+    #[cfg_attr(feature = "schema", schemars(with = "f32"))]
     pub cool_down: Duration,
     /// This state correspond to this meta range in the animation.
+    /// For a discontinuous meta track, use `<name>#<k>` to refer to its `k`-th range.
     pub state_meta: Cached<String, usize>,
     /// Transitions leaving this state.
     #[serde(default, skip_serializing_if = "defaults::is_slice_empty")]
@@ -187,6 +191,7 @@ macro_rules! cache_known_states {
 
 /// Transition from one state to another.
 #[derive(Debug, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StateTransition {
     /// Triggering condition for this transition. [`None`] means this transition should be
     /// automatically triggered immediately the cool-down completes (or when the animation finishes
@@ -207,6 +212,7 @@ pub struct StateTransition {
         skip_serializing_if = "defaults::is_zero_duration",
         with = "duration_from_secs"
     )]
+    #[cfg_attr(feature = "schema", schemars(with = "f32"))]
     pub cool_down: Duration,
     /// Destination for this transition.
     pub dest: Cached<String, usize>,
@@ -235,6 +241,7 @@ impl SortedSlice<StateTransition> {
 
 /// Attachment, useful for separating different movable parts in a single entity.
 #[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Attachment {
     /// Target track to which this model is attached.
     pub target_track: String,
@@ -249,6 +256,7 @@ impl EntryWithKey for Attachment {
 
 /// Plant meta information.
 #[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct PlantMeta {
     /// Width, number of grids taken by this plant, along the X axis (1 by default).
     #[serde(default = "defaults::one", skip_serializing_if = "defaults::is_one")]
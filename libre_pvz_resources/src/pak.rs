@@ -0,0 +1,334 @@
+/*
+ * librePvZ-resources: resource loading logics for librePvZ.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Package files (`main.pak`) in the original game, and an asset source backed by them.
+//!
+//! The original package is obfuscated by XOR-ing every byte with [`XOR_KEY`]. After
+//! de-obfuscation, the file starts with a header (magic and version), followed by a file table,
+//! and then the contents of all the files concatenated in the same order as in the table. Our own
+//! packages use the same layout, but without the obfuscation.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Take};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use bevy::prelude::*;
+use bevy::asset::io::{
+    AssetReader, AssetReaderError, AssetSource, AssetSourceBuilder, AssetSourceId,
+    ErasedAssetReader, PathStream, Reader, VecReader,
+};
+use bevy::tasks::futures_lite::stream;
+use parking_lot::Mutex;
+
+/// Key for the XOR obfuscation.
+pub const XOR_KEY: u8 = 0xF7;
+
+/// Magic number at the beginning of a package file.
+pub const PAK_MAGIC: u32 = 0xBA_C0_4A_C0;
+
+/// Flag in the file table marking the end of the table.
+const END_OF_TABLE: u8 = 0x80;
+
+/// De-obfuscate a stream XOR-ed with some key.
+#[derive(Debug)]
+pub struct XorReader<R> {
+    inner: R,
+    key: u8,
+}
+
+impl<R> XorReader<R> {
+    /// Create a reader XOR-ing every byte with `key`.
+    pub fn new(inner: R, key: u8) -> Self { XorReader { inner, key } }
+    /// Get back the underlying reader.
+    pub fn into_inner(self) -> R { self.inner }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        buf[..n].iter_mut().for_each(|b| *b ^= self.key);
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for XorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// An entry in the file table.
+#[derive(Debug, Clone)]
+pub struct PakEntry {
+    /// File path, separated by forward slashes (`/`), e.g., `reanim/PeaShooter.reanim.compiled`.
+    pub name: String,
+    /// File size in bytes.
+    pub size: u32,
+    /// Last modification time, as a Windows `FILETIME`.
+    pub file_time: u64,
+    /// Offset of the file contents from the beginning of the package.
+    pub offset: u64,
+}
+
+impl PakEntry {
+    /// Last modification time, if representable.
+    pub fn modified(&self) -> Option<SystemTime> {
+        // FILETIME counts 100ns intervals since 1601-01-01
+        const UNIX_EPOCH_AS_FILETIME: u64 = 116_444_736_000_000_000;
+        let since_epoch = self.file_time.checked_sub(UNIX_EPOCH_AS_FILETIME)?;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_nanos(since_epoch.checked_mul(100)?))
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut buffer = [0; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// A package file, with its file table loaded.
+#[derive(Debug)]
+pub struct PakArchive<R> {
+    reader: XorReader<R>,
+    entries: Vec<PakEntry>,
+}
+
+impl<R: Read + Seek> PakArchive<R> {
+    /// Open a package, and decode its file table. Obfuscation is detected automatically.
+    pub fn open(mut reader: R) -> std::io::Result<PakArchive<R>> {
+        let magic = u32::from_le_bytes(read_bytes(&mut reader)?);
+        let key = match magic {
+            PAK_MAGIC => 0,
+            _ if magic == PAK_MAGIC ^ u32::from_le_bytes([XOR_KEY; 4]) => XOR_KEY,
+            _ => return Err(invalid_data(format!("incorrect package magic: {magic:08X}"))),
+        };
+        let mut reader = XorReader::new(reader, key);
+        let version = u32::from_le_bytes(read_bytes(&mut reader)?);
+        if version != 0 {
+            warn!("unknown package version {version}, decoding anyway");
+        }
+        let mut header_size = 8;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let [flags] = read_bytes(&mut reader)?;
+            header_size += 1;
+            if flags & END_OF_TABLE != 0 { break; }
+            let [length] = read_bytes(&mut reader)?;
+            let mut name = vec![0_u8; length as usize];
+            reader.read_exact(&mut name)?;
+            let name = String::from_utf8(name)
+                .map_err(|err| invalid_data(format!("invalid file name in package: {err}")))?
+                .replace('\\', "/");
+            let size = u32::from_le_bytes(read_bytes(&mut reader)?);
+            let file_time = u64::from_le_bytes(read_bytes(&mut reader)?);
+            trace!("found file '{name}' of {size} bytes");
+            header_size += 1 + length as u64 + 4 + 8;
+            entries.push(PakEntry { name, size, file_time, offset });
+            offset += size as u64;
+        }
+        entries.iter_mut().for_each(|entry| entry.offset += header_size);
+        Ok(PakArchive { reader, entries })
+    }
+
+    /// Is this package obfuscated (as in the original game)?
+    pub fn is_obfuscated(&self) -> bool { self.reader.key != 0 }
+
+    /// All the entries in the file table.
+    pub fn entries(&self) -> &[PakEntry] { &self.entries }
+
+    /// Find an entry by its path (case-insensitive, as is in the original game).
+    pub fn find(&self, name: &str) -> Option<&PakEntry> {
+        let name = name.replace('\\', "/");
+        self.entries.iter().find(|entry| entry.name.eq_ignore_ascii_case(&name))
+    }
+
+    /// Open an entry for reading its contents.
+    pub fn open_entry(&mut self, entry: &PakEntry) -> std::io::Result<Take<&mut XorReader<R>>> {
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        Ok((&mut self.reader).take(entry.size as u64))
+    }
+
+    /// Read the contents of an entry into memory.
+    pub fn read_entry(&mut self, entry: &PakEntry) -> std::io::Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(entry.size as usize);
+        self.open_entry(entry)?.read_to_end(&mut buffer)?;
+        if buffer.len() < entry.size as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("truncated file '{}' in package", entry.name),
+            ));
+        }
+        Ok(buffer)
+    }
+}
+
+/// Normalise a path for case-insensitive lookup in packages.
+fn lookup_key(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/").trim_matches('/').to_ascii_lowercase()
+}
+
+/// A package shared among the [`PakAssetReader`]s, indexed for fast lookup.
+#[derive(Debug)]
+struct SharedPak {
+    archive: Mutex<PakArchive<BufReader<File>>>,
+    /// Copy of the file table, so that lookups need not wait for the lock.
+    entries: Box<[PakEntry]>,
+    /// Lower-cased paths to indices into the file table.
+    index: HashMap<String, usize>,
+    search_dirs: Vec<String>,
+}
+
+impl SharedPak {
+    fn open(path: &Path, search_dirs: &[String]) -> std::io::Result<SharedPak> {
+        let archive = PakArchive::open(BufReader::new(File::open(path)?))?;
+        let index = archive.entries().iter().enumerate()
+            .map(|(k, entry)| (entry.name.to_ascii_lowercase(), k))
+            .collect();
+        let search_dirs = search_dirs.iter().map(|dir| lookup_key(dir.as_ref())).collect();
+        let entries = archive.entries().into();
+        Ok(SharedPak { archive: Mutex::new(archive), entries, index, search_dirs })
+    }
+
+    /// Find a file in the package, trying also the search directories in order.
+    fn find(&self, path: &Path) -> Option<usize> {
+        let key = lookup_key(path);
+        std::iter::once(key.clone())
+            .chain(self.search_dirs.iter().map(|dir| format!("{dir}/{key}")))
+            .find_map(|key| self.index.get(&key).copied())
+    }
+
+    /// Read a file from the package. This blocks on both the lock and the file I/O.
+    fn read(&self, k: usize) -> std::io::Result<Vec<u8>> {
+        self.archive.lock().read_entry(&self.entries[k])
+    }
+
+    /// Direct children of a directory in the package, with their original case.
+    fn children(&self, dir: &Path) -> Option<Vec<PathBuf>> {
+        let mut prefix = lookup_key(dir);
+        if !prefix.is_empty() { prefix.push('/'); }
+        let mut children = self.entries.iter()
+            .filter(|entry| entry.name.to_ascii_lowercase().starts_with(&prefix))
+            .map(|entry| entry.name[prefix.len()..].split('/').next().unwrap_or_default())
+            .map(|name| dir.join(name))
+            .collect::<Vec<_>>();
+        children.sort();
+        children.dedup();
+        (!children.is_empty()).then_some(children)
+    }
+}
+
+/// An [`AssetReader`] serving files from a package, and falling back to another reader (usually
+/// the file system) for files not found in the package.
+pub struct PakAssetReader {
+    pak: Arc<SharedPak>,
+    fallback: Box<dyn ErasedAssetReader>,
+}
+
+impl std::fmt::Debug for PakAssetReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PakAssetReader").field("pak", &self.pak).finish_non_exhaustive()
+    }
+}
+
+impl AssetReader for PakAssetReader {
+    async fn read<'a>(&'a self, path: &'a Path) -> Result<Box<Reader<'a>>, AssetReaderError> {
+        match self.pak.find(path) {
+            Some(k) => {
+                // reading is blocking, keep it off the async executor (like 'async-fs' does)
+                let pak = self.pak.clone();
+                let bytes = blocking::unblock(move || pak.read(k)).await
+                    .map_err(|err| AssetReaderError::Io(Arc::new(err)))?;
+                Ok(Box::new(VecReader::new(bytes)))
+            }
+            None => self.fallback.read(path).await,
+        }
+    }
+
+    async fn read_meta<'a>(&'a self, path: &'a Path) -> Result<Box<Reader<'a>>, AssetReaderError> {
+        // packages never contain meta files
+        self.fallback.read_meta(path).await
+    }
+
+    async fn read_directory<'a>(&'a self, path: &'a Path) -> Result<Box<PathStream>, AssetReaderError> {
+        match self.pak.children(path) {
+            Some(children) => Ok(Box::new(stream::iter(children))),
+            None => self.fallback.read_directory(path).await,
+        }
+    }
+
+    async fn is_directory<'a>(&'a self, path: &'a Path) -> Result<bool, AssetReaderError> {
+        if self.pak.find(path).is_some() { return Ok(false); }
+        if self.pak.children(path).is_some() { return Ok(true); }
+        self.fallback.is_directory(path).await
+    }
+}
+
+/// Plugin registering a [`PakAssetReader`] as the default asset source. Must be added before
+/// bevy's [`AssetPlugin`] (typically as part of `DefaultPlugins`).
+///
+/// If the package cannot be opened, an error is logged, and the default asset source is left
+/// untouched, i.e., all the assets are loaded from the file system.
+#[derive(Debug, Clone)]
+pub struct PakAssetPlugin {
+    /// Path to the package file, e.g., `main.pak`.
+    pub archive: PathBuf,
+    /// Directories in the package to search for assets not found at the exact path, e.g., with
+    /// `images` here, `SeedBank.png` resolves to `images/SeedBank.png` in the package.
+    pub search_dirs: Vec<String>,
+    /// Asset directory on the file system, used for assets not found in the package. Should agree
+    /// with [`AssetPlugin::file_path`].
+    pub fallback_path: String,
+}
+
+impl PakAssetPlugin {
+    /// Serve assets from the package at `archive`, with default search directories.
+    pub fn new(archive: impl Into<PathBuf>) -> Self {
+        PakAssetPlugin {
+            archive: archive.into(),
+            search_dirs: vec!["images".to_string(), "reanim".to_string()],
+            fallback_path: "assets".to_string(),
+        }
+    }
+}
+
+impl Plugin for PakAssetPlugin {
+    fn build(&self, app: &mut App) {
+        // logging is not yet set up at this point, so we report in a startup system instead
+        let archive = self.archive.clone();
+        let pak = match SharedPak::open(&self.archive, &self.search_dirs) {
+            Ok(pak) => Arc::new(pak),
+            Err(err) => {
+                let message = format!("failed to open package {archive:?}, using the file system: {err}");
+                app.add_systems(Startup, move || error!("{message}"));
+                return;
+            }
+        };
+        app.add_systems(Startup, move || info!("serving assets from package {archive:?}"));
+        let mut fallback = AssetSource::get_default_reader(self.fallback_path.clone());
+        let source = AssetSourceBuilder::platform_default(&self.fallback_path, None)
+            .with_reader(move || Box::new(PakAssetReader { pak: pak.clone(), fallback: fallback() }));
+        app.register_asset_source(AssetSourceId::Default, source);
+    }
+}
@@ -0,0 +1,91 @@
+/*
+ * librePvZ-resources: resource loading logics for librePvZ.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Float parameters changing over time, shared by particles and trails.
+
+use bincode::{Encode, Decode};
+use serde::{Serialize, Deserialize};
+
+/// Interpolation curves between nodes in a [`FloatTrack`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(missing_docs)]
+pub enum Curve {
+    /// Stay constant at the start value.
+    Constant,
+    /// Linear interpolation.
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    EaseInOutWeak,
+    FastInOut,
+    FastInOutWeak,
+    WeakFastInOut,
+    Bounce,
+    BounceFastMiddle,
+    BounceSlowMiddle,
+    SinWave,
+    EaseSinWave,
+}
+
+impl Curve {
+    /// All the curves, in the order of their numeric values in the original game.
+    pub const ALL: [Curve; 14] = {
+        use Curve::*;
+        [
+            Constant, Linear, EaseIn, EaseOut, EaseInOut, EaseInOutWeak, FastInOut, FastInOutWeak,
+            WeakFastInOut, Bounce, BounceFastMiddle, BounceSlowMiddle, SinWave, EaseSinWave,
+        ]
+    };
+}
+
+impl TryFrom<u32> for Curve {
+    type Error = u32;
+    fn try_from(n: u32) -> Result<Curve, u32> {
+        Curve::ALL.get(n as usize).copied().ok_or(n)
+    }
+}
+
+/// A node in a [`FloatTrack`].
+#[derive(Debug, Copy, Clone, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+pub struct TrackNode {
+    /// Time of this node, between 0 and 1 (relative to the lifetime).
+    pub time: f32,
+    /// Lower bound for the value.
+    pub low: f32,
+    /// Upper bound for the value.
+    pub high: f32,
+    /// Interpolation curve from this node to the next.
+    pub curve: Curve,
+    /// Distribution of values between `low` and `high`.
+    pub distribution: Curve,
+}
+
+/// A float parameter changing over time.
+#[derive(Debug, Default, Clone, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FloatTrack(pub Box<[TrackNode]>);
+
+impl FloatTrack {
+    /// Is this track left unspecified?
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
@@ -0,0 +1,202 @@
+/*
+ * librePvZ-resources: resource loading logics for librePvZ.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Localized strings (`properties/LawnStrings.txt`).
+//!
+//! All the texts in the original game come from a string table, where each entry starts with its
+//! key in square brackets on a line of its own, followed by the text (possibly multiple lines):
+//!
+//! ```text
+//! [PEASHOOTER]
+//! Peashooter
+//!
+//! [PEASHOOTER_DESCRIPTION]
+//! Shoots peas at zombies.{SHORTLINE}
+//! {KEYWORD}Damage:{STAT} normal
+//! ```
+//!
+//! Texts may contain markup in curly braces (e.g., `{KEYWORD}` for a change of colour), which are
+//! kept as is in the table, and can be processed using [`spans`].
+
+use std::collections::HashMap;
+use anyhow::{Context, Error, Result};
+use bevy::prelude::*;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
+use bevy::asset::io::Reader;
+use bevy::utils::ConditionalSendFuture;
+
+/// String table plugin.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct StringsPlugin;
+
+impl Plugin for StringsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<StringTable>()
+            .register_asset_loader(StringTableLoader)
+            .init_resource::<Localization>();
+    }
+}
+
+/// A string table for one language, mapping keys (without brackets) to texts.
+#[derive(Asset, TypePath, Debug, Default, Clone)]
+pub struct StringTable {
+    entries: HashMap<String, String>,
+}
+
+impl StringTable {
+    /// Parse a string table. Leading and trailing blank lines in the texts, as well as trailing
+    /// whitespace in each line, are removed, and lines are joined with `'\n'`.
+    pub fn parse(src: &str) -> Result<StringTable> {
+        let mut entries = HashMap::new();
+        let mut current: Option<(String, Vec<&str>)> = None;
+        let mut finish = |current: Option<(String, Vec<&str>)>| -> Result<()> {
+            let Some((key, lines)) = current else { return Ok(()) };
+            let start = lines.iter().position(|line| !line.is_empty()).unwrap_or(lines.len());
+            let end = lines.iter().rposition(|line| !line.is_empty()).map_or(start, |k| k + 1);
+            let text = lines[start..end].join("\n");
+            anyhow::ensure!(entries.insert(key.clone(), text).is_none(), "duplicate key [{key}]");
+            Ok(())
+        };
+        for (k, line) in src.lines().enumerate() {
+            // leading whitespace might be intentional, e.g., for indentation
+            let line = line.trim_end();
+            if let Some(key) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                finish(current.take())?;
+                anyhow::ensure!(!key.is_empty(), "line {}: empty key", k + 1);
+                current = Some((key.to_string(), Vec::new()));
+            } else if let Some((_, lines)) = &mut current {
+                lines.push(line);
+            } else {
+                anyhow::ensure!(line.is_empty(), "line {}: text before the first key", k + 1);
+            }
+        }
+        finish(current)?;
+        Ok(StringTable { entries })
+    }
+
+    /// Number of entries in this table.
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    /// Whether this table is empty.
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Get the text for some key, e.g., `"PEASHOOTER"`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    /// All the keys in this table, in no particular order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+}
+
+/// Markup-aware view of a text, see [`spans`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Span<'a> {
+    /// Plain text.
+    Text(&'a str),
+    /// Markup, without the curly braces, e.g., `"SHORTLINE"` for `{SHORTLINE}`.
+    Markup(&'a str),
+}
+
+/// Split a text into plain text and markup. An unclosed `{` is treated as plain text.
+pub fn spans(text: &str) -> impl Iterator<Item = Span<'_>> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() { return None; }
+        if let Some(markup) = rest.strip_prefix('{') {
+            if let Some(end) = markup.find(['{', '}']).filter(|&end| markup[end..].starts_with('}')) {
+                rest = &markup[end + 1..];
+                return Some(Span::Markup(&markup[..end]));
+            }
+        }
+        // skip an unclosed '{', so that it is not matched again
+        let skip = usize::from(rest.starts_with('{'));
+        let len = rest[skip..].find('{').map_or(rest.len(), |k| k + skip);
+        let (text, remaining) = rest.split_at(len);
+        rest = remaining;
+        Some(Span::Text(text))
+    })
+}
+
+/// Strip all the markup from a text.
+pub fn strip_markup(text: &str) -> String {
+    spans(text).filter_map(|span| match span {
+        Span::Text(text) => Some(text),
+        Span::Markup(_) => None,
+    }).collect()
+}
+
+/// String tables for the languages in use, preferred ones first. Lookup falls back to later tables
+/// if a key is missing (or if a table is not yet loaded), e.g., from a partial translation to the
+/// original English texts.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct Localization {
+    /// The fallback chain.
+    pub chain: Vec<Handle<StringTable>>,
+}
+
+impl Localization {
+    /// Get the text for some key, from the first table containing it.
+    pub fn get<'a>(&self, tables: &'a Assets<StringTable>, key: &str) -> Option<&'a str> {
+        self.chain.iter()
+            .filter_map(|table| tables.get(table))
+            .find_map(|table| table.get(key))
+    }
+
+    /// Translate a text: texts in the form `[KEY]` are looked up, and others are returned as is.
+    /// Missing keys are also returned as is, so that they are easy to spot.
+    pub fn translate<'a>(&self, tables: &'a Assets<StringTable>, text: &'a str) -> &'a str {
+        text.strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|key| self.get(tables, key))
+            .unwrap_or(text)
+    }
+}
+
+/// Loader for [`StringTable`]s. Only the extension `.strings.txt` is claimed, so the original
+/// `LawnStrings.txt` should be loaded with an explicit asset type, i.e., `load::<StringTable>`.
+/// Files not in UTF-8 are decoded as Latin-1, as is the case for the original game.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct StringTableLoader;
+
+impl AssetLoader for StringTableLoader {
+    type Asset = StringTable;
+    type Settings = ();
+    type Error = Error;
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a Self::Settings,
+        load_context: &'a mut LoadContext,
+    ) -> impl ConditionalSendFuture<Output = Result<StringTable>> {
+        async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let src = match String::from_utf8(bytes) {
+                Ok(src) => src,
+                Err(err) => err.into_bytes().into_iter().map(char::from).collect(),
+            };
+            let src = src.strip_prefix('\u{FEFF}').unwrap_or(&src);
+            StringTable::parse(src)
+                .with_context(|| format!("failed to parse string table {:?}", load_context.path()))
+        }
+    }
+    fn extensions(&self) -> &[&str] { &["strings.txt"] }
+}
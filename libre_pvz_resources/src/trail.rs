@@ -0,0 +1,87 @@
+/*
+ * librePvZ-resources: resource loading logics for librePvZ.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Trails, e.g., streaks behind projectiles.
+
+use std::path::PathBuf;
+use bevy::prelude::*;
+use bevy::asset::{AssetPath, LoadContext};
+use bincode::{Encode, Decode};
+use serde::{Serialize, Deserialize};
+use crate::asset_ext;
+use crate::cached::Cached;
+use crate::loader::{AddTwoStageAsset, AssetExtensions, TwoStageAsset};
+use crate::param::FloatTrack;
+
+/// Trail plugin.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct TrailPlugin;
+
+impl Plugin for TrailPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_two_stage_asset::<Trail>();
+    }
+}
+
+/// Trails, originally in `.trail` format.
+#[derive(Debug, Encode, Decode)]
+#[derive(Serialize, Deserialize)]
+pub struct TrailDesc {
+    /// Image stretched along the trail.
+    pub image: Cached<PathBuf, Handle<Image>>,
+    /// Maximum number of points in the trail.
+    pub max_points: u32,
+    /// Minimum distance between two consecutive points.
+    pub min_point_distance: f32,
+    /// Whether or not the trail restarts when its duration elapses.
+    #[serde(default)]
+    pub loops: bool,
+    /// Lifetime of the trail, in centiseconds.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub duration: FloatTrack,
+    /// Width along the trail.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub width_over_length: FloatTrack,
+    /// Width over the lifetime.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub width_over_time: FloatTrack,
+    /// Alpha along the trail.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub alpha_over_length: FloatTrack,
+    /// Alpha over the lifetime.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub alpha_over_time: FloatTrack,
+}
+
+/// Trail and its image.
+#[derive(Asset, TypePath)]
+#[allow(missing_debug_implementations)]
+pub struct Trail {
+    /// The trail description.
+    pub description: TrailDesc,
+}
+
+impl TwoStageAsset for Trail {
+    type Repr = TrailDesc;
+    const EXTENSIONS: AssetExtensions = asset_ext!("trail");
+    fn post_process(trail: TrailDesc, load_context: &mut LoadContext) -> anyhow::Result<(Trail, Vec<AssetPath<'static>>)> {
+        trail.image.init_handle(load_context);
+        let dep_paths = vec![trail.image.asset_path().into_owned()];
+        Ok((Trail { description: trail }, dep_paths))
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Derive macros for `reanim-decode`.
+
+#![warn(missing_docs)]
+
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, LitStr, Path, Type};
+use syn::spanned::Spanned;
+
+/// Derive `reanim_decode::stream::Decode` for a struct with named fields.
+///
+/// Fields are decoded in their declaration order, each within a `field '<name>'` decoding context.
+/// By default, a field of type `T` is decoded with `<T as Decode<()>>`. Supported attributes:
+///
+/// - `#[decode(args = Type)]` on the struct: implement `Decode<Type>` instead of `Decode<()>`. The
+///   arguments are available as `args` in field attributes.
+/// - `#[decode(magic = expr)]` on the struct: check a magic number before all the fields.
+///
+/// On fields (magic numbers and paddings are read in the order listed here):
+///
+/// - `#[decode(magic = expr)]`: check a magic number before this field.
+/// - `#[decode(padding = n)]`: drop `n` bytes of padding before this field.
+/// - `#[decode(optional)]`: decode an `Option<T>` with `T: DecodeOptional`, e.g., empty strings
+///   as [`None`]. Note that `Option<f32>` can already be decoded directly (`-10000` as [`None`]).
+/// - `#[decode(args = expr)]`: decode with `<T as Decode<_>>::decode_with(s, expr)`.
+/// - `#[decode(with = path)]`: decode with `path(s)`, or `path(s, expr)` if `args` is specified.
+/// - `#[decode(padding_after = n)]`: drop `n` bytes of padding after this field.
+/// - `#[decode(context = "kind")]`: the remaining fields are decoded in the `kind '<value>'`
+///   context, where `<value>` is the value of this field, e.g., the name of a track.
+/// - `#[decode(no_context)]`: do not introduce the `field '<name>'` context for this field.
+///
+/// ```ignore
+/// #[derive(Decode)]
+/// #[decode(args = usize)]
+/// pub struct Track {
+///     #[decode(context = "track")]
+///     pub name: String,
+///     #[decode(magic = 0x2C, with = decode_frames, args = args, no_context)]
+///     pub frames: Box<[Frame]>,
+/// }
+/// ```
+#[proc_macro_derive(Decode, attributes(decode))]
+pub fn derive_decode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive_decode_impl(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+#[derive(Default)]
+struct ContainerAttrs {
+    args: Option<Type>,
+    magic: Option<Expr>,
+}
+
+#[derive(Default)]
+struct FieldAttrs {
+    magic: Option<Expr>,
+    padding: Option<Expr>,
+    optional: bool,
+    args: Option<Expr>,
+    with: Option<Path>,
+    padding_after: Option<Expr>,
+    context: Option<LitStr>,
+    no_context: bool,
+}
+
+fn parse_container_attrs(input: &DeriveInput) -> syn::Result<ContainerAttrs> {
+    let mut attrs = ContainerAttrs::default();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("decode")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("args") {
+                attrs.args = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("magic") {
+                attrs.magic = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("unsupported attribute, expecting 'args' or 'magic'"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attrs)
+}
+
+fn parse_field_attrs(field: &syn::Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("decode")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("magic") {
+                attrs.magic = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("padding") {
+                attrs.padding = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("optional") {
+                attrs.optional = true;
+            } else if meta.path.is_ident("args") {
+                attrs.args = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("with") {
+                attrs.with = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("padding_after") {
+                attrs.padding_after = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("context") {
+                attrs.context = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("no_context") {
+                attrs.no_context = true;
+            } else {
+                return Err(meta.error("unsupported attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    if attrs.optional && attrs.with.is_some() {
+        return Err(syn::Error::new(field.span(), "'optional' conflicts with 'with'"));
+    }
+    Ok(attrs)
+}
+
+fn derive_decode_impl(input: DeriveInput) -> syn::Result<TokenStream> {
+    let container = parse_container_attrs(&input)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new(input.ident.span(), "only structs with named fields are supported")),
+        },
+        _ => return Err(syn::Error::new(input.ident.span(), "only structs are supported")),
+    };
+
+    let krate = quote!(::reanim_decode::stream);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let args_type = container.args.map_or_else(|| quote!(()), |ty| quote!(#ty));
+    let magic = container.magic.map(|magic| quote!(#krate::Stream::check_magic(s, #magic)?;));
+
+    let field_names = fields.iter().map(|field| field.ident.clone().unwrap()).collect::<Vec<_>>();
+    let field_attrs = fields.iter().map(parse_field_attrs).collect::<syn::Result<Vec<_>>>()?;
+
+    // build the body from the last field to the first, so that contexts can wrap the rest
+    let mut body = quote!(Ok(#name { #(#field_names),* }));
+    for ((field, ident), attrs) in fields.iter().zip(&field_names).zip(&field_attrs).rev() {
+        let ty = &field.ty;
+        let hint = ident.to_string();
+        let span = field.span();
+        let args = attrs.args.as_ref().map_or_else(|| quote!(()), |args| quote!(#args));
+        let decode = match (&attrs.with, attrs.optional) {
+            (Some(with), _) if attrs.args.is_some() => quote_spanned!(span=> #with(s, #args)),
+            (Some(with), _) => quote_spanned!(span=> #with(s)),
+            (None, true) => quote_spanned!(span=> #krate::DecodeOptional::decode_optional(s)),
+            (None, false) => quote_spanned!(span=> <#ty as #krate::Decode<_>>::decode_with(s, #args)),
+        };
+        let decode = if attrs.no_context { decode } else {
+            quote!(#krate::Stream::with_context(s, #krate::Context::Field(#hint), |s| #decode))
+        };
+        let magic = attrs.magic.as_ref().map(|magic| quote!(#krate::Stream::check_magic(s, #magic)?;));
+        let padding = attrs.padding.as_ref()
+            .map(|n| quote!(#krate::Stream::drop_padding(s, #hint, #n)?;));
+        let padding_after = attrs.padding_after.as_ref()
+            .map(|n| quote!(#krate::Stream::drop_padding(s, #hint, #n)?;));
+        let rest = match &attrs.context {
+            None => body,
+            Some(kind) => quote! {
+                let __context = #krate::Context::Named(#kind, ::std::string::ToString::to_string(&#ident));
+                #krate::Stream::with_context(s, __context, |s| { #body })
+            },
+        };
+        body = quote! {
+            #magic
+            #padding
+            let #ident: #ty = #decode?;
+            #padding_after
+            #rest
+        };
+    }
+
+    Ok(quote! {
+        impl #impl_generics #krate::NamedArgs for #name #ty_generics #where_clause {
+            type ArgsBuilder = #krate::NoArgs;
+            fn args_builder() -> #krate::NoArgs { #krate::NoArgs }
+        }
+
+        impl #impl_generics #krate::Decode<#args_type> for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn decode_with<S: #krate::Stream + ?Sized>(s: &mut S, args: #args_type) -> #krate::Result<Self> {
+                #magic
+                #body
+            }
+        }
+    })
+}
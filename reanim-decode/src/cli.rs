@@ -18,19 +18,47 @@
 
 //! Command line interface for `reanim-decode`.
 
+pub mod batch;
+pub mod diff;
+pub mod info;
+pub mod lint;
+pub mod pak;
+#[cfg(feature = "render")]
+pub mod render;
+pub mod scaffold;
+pub mod schema;
+
 use std::ffi::OsStr;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use anyhow::Context;
 use clap::{ValueEnum, Parser, Subcommand};
 use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
 use serde::{Serialize, Serializer};
 use libre_pvz_resources::animation as packed;
+use libre_pvz_resources::font::BitmapFont;
+use libre_pvz_resources::loader::{decode_bin, encode_bin, encode_bin_with, BinHeader, BIN_MAGIC, TwoStageAsset};
 use libre_pvz_resources::model;
-use crate::reanim::Animation;
-use crate::xml::Xml as XmlWrapper;
+use libre_pvz_resources::trail::Trail;
+use crate::font::FontDefinition;
+use crate::optimize::optimize_anim;
+use crate::particles::ParticleDefinition;
+use crate::reanim::{Animation, ImageResolver, ReanimNameHeuristic};
+use crate::resources::{ManifestResolver, ResourceManifest};
+use crate::trail::TrailDefinition;
+use crate::xml::{FromXml, Xml as XmlWrapper};
+use batch::{BatchArgs, PackWarningCounter};
+use diff::DiffArgs;
+use info::InfoArgs;
+use lint::LintArgs;
+use pak::{ExtractArgs, ListArgs};
+#[cfg(feature = "render")]
+use render::RenderArgs;
+use scaffold::ScaffoldArgs;
+use schema::SchemaArgs;
 
 /// Optionally packed animations.
 pub enum MaybePacked {
@@ -46,11 +74,13 @@ impl MaybePacked {
     /// Is this already packed?
     pub fn is_packed(&self) -> bool { matches!(self, Packed(_)) }
 
-    /// Force this to be packed, fail if unpacking is requested.
-    pub fn into_packed(self, packed: bool) -> anyhow::Result<MaybePacked> {
+    /// Pack or unpack this animation as requested.
+    /// Image names are resolved (or mapped back when unpacking) using the [`ImageResolver`].
+    pub fn into_packed<R>(self, packed: bool, resolver: &R) -> anyhow::Result<MaybePacked>
+        where R: ImageResolver + ?Sized {
         Ok(match self {
-            Packed(_) if !packed => anyhow::bail!("unpacking not supported"),
-            Plain(anim) if packed => Packed(anim.into()),
+            Packed(anim) if !packed => Plain(Animation::unpack_with(anim, resolver)),
+            Plain(anim) if packed => Packed(anim.pack_with(resolver)),
             _ => self,
         })
     }
@@ -140,10 +170,16 @@ impl Display for Format {
 impl Format {
     /// Infer whether or not the output should be packed.
     pub fn infer_packed<P: AsRef<Path>>(path: P) -> bool {
+        Format::infer_packed_as(path, "anim")
+    }
+
+    /// Infer whether or not the output should be packed, for assets of some resource type (the
+    /// secondary extension, e.g., `"trail"` for `*.trail.json`).
+    pub fn infer_packed_as<P: AsRef<Path>>(path: P, kind: &str) -> bool {
         let file = path.as_ref();
         let ext = file.extension().and_then(OsStr::to_str);
         let stem = file.file_stem().and_then(OsStr::to_str);
-        ext == Some("bin") || stem.map_or(false, |s| s.ends_with(".anim"))
+        ext == Some("bin") || stem.and_then(|s| s.strip_suffix(kind)).is_some_and(|s| s.ends_with('.'))
     }
 
     /// Infer a format from given file name.
@@ -201,14 +237,99 @@ pub enum Commands {
         /// Use structural format for output.
         #[clap(long)]
         pack_output: bool,
+        /// Do not compress the output (only for the compiled format).
+        #[clap(long)]
+        uncompressed: bool,
+        /// Resolve image names using the resource manifest (`properties/resources.xml`).
+        #[clap(long)]
+        resources: Option<PathBuf>,
+        /// Drop redundant and linearly interpolable keyframes.
+        #[clap(long)]
+        optimize: bool,
+        /// Tolerance for dropping keyframes (with `--optimize`).
+        #[clap(long, default_value_t = 1e-3)]
+        tolerance: f32,
+    },
+    /// Conversion for particle definitions.
+    Particles {
+        /// Input file path.
+        input: PathBuf,
+        /// Input format.
+        #[clap(short = 'I', long, value_enum)]
+        input_format: Option<Format>,
+        /// Output file path.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Output format.
+        #[clap(short = 'O', long, value_enum)]
+        output_format: Option<Format>,
+    },
+    /// Conversion for trail definitions.
+    Trail {
+        /// Input file path.
+        input: PathBuf,
+        /// Input format.
+        #[clap(short = 'I', long, value_enum)]
+        input_format: Option<Format>,
+        /// Output file path.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Output format.
+        #[clap(short = 'O', long, value_enum)]
+        output_format: Option<Format>,
+        /// Use structural format for output (always the case for 'bin', and inferred from output
+        /// file names like `*.trail.json`).
+        #[clap(long)]
+        pack_output: bool,
+        /// Resolve image names using the resource manifest (`properties/resources.xml`).
+        #[clap(long)]
+        resources: Option<PathBuf>,
+    },
+    /// Conversion for bitmap font definitions (font description scripts, `.txt`).
+    Font {
+        /// Input file path.
+        input: PathBuf,
+        /// Output file path.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Output format.
+        #[clap(short = 'O', long, value_enum)]
+        output_format: Option<Format>,
+        /// Use structural format for output (always the case for 'bin', and inferred from output
+        /// file names like `*.font.json`). Image paths are resolved against the directory of
+        /// the input file, which should be relative to the asset directory.
+        #[clap(long)]
+        pack_output: bool,
     },
+    /// Summarize an animation: meta data, tracks, and referenced images.
+    Info(InfoArgs),
+    /// Validate a model against its animation and child models.
+    Lint(LintArgs),
+    /// Generate a starter model from the meta tracks of an animation.
+    Scaffold(ScaffoldArgs),
+    /// Compare two animations track-by-track and frame-by-frame.
+    Diff(DiffArgs),
+    /// Print JSON Schema for models or animations, for validation and auto-completion in editors.
+    Schema(SchemaArgs),
+    /// Render an animation to a single frame, a sprite sheet, or an animated GIF.
+    #[cfg(feature = "render")]
+    Render(RenderArgs),
+    /// Batch conversion for a whole directory.
+    Batch(BatchArgs),
+    /// List files in a package (`main.pak`).
+    List(ListArgs),
+    /// Extract files from a package (`main.pak`).
+    Extract(ExtractArgs),
 }
 
 fn setup_logger(verbose: LevelFilter) {
-    tracing_subscriber::fmt::Subscriber::builder()
+    let fmt = tracing_subscriber::fmt::layer()
         .with_target(true)
         .without_time()
-        .with_max_level(verbose)
+        .with_filter(verbose);
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(PackWarningCounter.with_filter(LevelFilter::WARN))
         .init()
 }
 
@@ -231,14 +352,7 @@ impl Cli {
             } => {
                 // open input & decode
                 let input_format = Format::decide(input_format, Some(&input), Bin);
-                let input = File::open(&input).with_context(|| format!("failed to read file {input:?}"))?;
-                let mut input = BufReader::new(input);
-                let model: model::Model = match input_format {
-                    Internal | Compiled | Xml => anyhow::bail!("unsupported input format: {input_format}"),
-                    Bin => bincode::decode_from_std_read(&mut input, BINCODE_CONFIG)?,
-                    Json => serde_json::from_reader(&mut input)?,
-                    Yaml => serde_yaml::from_reader(&mut input)?,
-                };
+                let model = decode_model(&input, input_format)?;
 
                 // infer output format
                 let output_format = Format::decide(output_format, output.as_ref(), Internal);
@@ -254,51 +368,233 @@ impl Cli {
             Commands::Anim {
                 input, input_format, mut pack_input,
                 output_format, output, mut pack_output,
+                uncompressed, resources,
+                optimize, tolerance,
             } => {
                 // open input & decode
                 pack_input |= Format::infer_packed(&input);
                 let input_format = Format::decide(input_format, Some(&input), Compiled);
-                let input = File::open(&input).with_context(|| format!("failed to read file {input:?}"))?;
-                let mut input = BufReader::new(input);
-                let anim = match input_format {
-                    Internal | Xml => anyhow::bail!("unsupported input format: {input_format}"),
-                    Bin => Packed(bincode::decode_from_std_read(&mut input, BINCODE_CONFIG)?),
-                    Compiled => Plain(Animation::decompress_and_decode(&mut input)?),
-                    Json if pack_input => Packed(serde_json::from_reader(&mut input)?),
-                    Yaml if pack_input => Packed(serde_yaml::from_reader(&mut input)?),
-                    Json => Plain(serde_json::from_reader(&mut input)?),
-                    Yaml => Plain(serde_yaml::from_reader(&mut input)?),
-                };
+                let anim = decode_anim(&input, input_format, pack_input)?;
 
                 // infer output format
-                pack_output |= output.as_ref().map_or(anim.is_packed(), Format::infer_packed);
+                let infer_packed = output.as_ref().map_or(anim.is_packed(), Format::infer_packed);
                 let output_format = Format::decide(
                     output_format, output.as_ref(),
-                    if pack_output { Internal } else { Xml },
+                    if pack_output || infer_packed { Internal } else { Xml },
                 );
-                let anim = anim.into_packed(pack_output)?;
+                // reanim formats only support the plain form, unpack unless explicitly requested
+                pack_output |= infer_packed && !matches!(output_format, Compiled | Xml);
+                let anim = with_resolver(resources.as_deref(), |resolver| {
+                    let mut anim = anim.into_packed(pack_output || optimize, resolver)?;
+                    if let (true, MaybePacked::Packed(anim)) = (optimize, &mut anim) {
+                        let stats = optimize_anim(anim, tolerance);
+                        tracing::info!("optimized keyframes: {stats}");
+                    }
+                    anim.into_packed(pack_output, resolver)
+                })?;
 
                 // output file (or stdout)
                 if let Some(output) = output {
                     let context = || format!("failed to open output file {output:?}");
                     let output = File::create(&output).with_context(context)?;
-                    encode_anim(anim, output_format, output)?;
+                    encode_anim(anim, output_format, !uncompressed, output)?;
                 } else {
-                    encode_anim(anim, output_format, std::io::stdout().lock())?;
+                    encode_anim(anim, output_format, !uncompressed, std::io::stdout().lock())?;
                 }
             }
+            Commands::Particles {
+                input, input_format,
+                output_format, output,
+            } => {
+                // open input & decode
+                let input_format = Format::decide(input_format, Some(&input), Compiled);
+                let particles = decode_particles(&input, input_format)?;
+
+                // infer output format
+                let output_format = Format::decide(output_format, output.as_ref(), Internal);
+                // output file (or stdout)
+                if let Some(output) = output {
+                    let context = || format!("failed to open output file {output:?}");
+                    let output = File::create(&output).with_context(context)?;
+                    encode_particles(particles, output_format, output)?;
+                } else {
+                    encode_particles(particles, output_format, std::io::stdout().lock())?;
+                }
+            }
+            Commands::Trail {
+                input, input_format,
+                output_format, output,
+                mut pack_output, resources,
+            } => {
+                // open input & decode
+                let input_format = Format::decide(input_format, Some(&input), Compiled);
+                let trail = decode_trail(&input, input_format)?;
+
+                // infer output format, 'bincode' is only meant for loading as assets
+                let output_format = Format::decide(output_format, output.as_ref(), Internal);
+                pack_output |= output_format == Bin
+                    || output.as_ref().is_some_and(|output| Format::infer_packed_as(output, Trail::EXTENSIONS.kind));
+                let mut output: Box<dyn Write> = match output {
+                    Some(output) => {
+                        let context = || format!("failed to open output file {output:?}");
+                        Box::new(File::create(&output).with_context(context)?)
+                    }
+                    None => Box::new(std::io::stdout().lock()),
+                };
+                if pack_output {
+                    let trail = with_resolver(resources.as_deref(), |resolver| Ok(trail.pack_with(resolver)))?;
+                    encode_bin_asset(&trail, Some(&BinHeader::of::<Trail>()), output_format, &mut output)?;
+                } else {
+                    encode_bin_asset(&trail, None, output_format, &mut output)?;
+                }
+            }
+            Commands::Font { input, output_format, output, mut pack_output } => {
+                // open input & decode
+                let font = decode_font(&input)?;
+
+                // infer output format, 'bincode' is only meant for loading as assets
+                let output_format = Format::decide(output_format, output.as_ref(), Internal);
+                pack_output |= output_format == Bin
+                    || output.as_ref().is_some_and(|output| Format::infer_packed_as(output, BitmapFont::EXTENSIONS.kind));
+                let mut output: Box<dyn Write> = match output {
+                    Some(output) => {
+                        let context = || format!("failed to open output file {output:?}");
+                        Box::new(File::create(&output).with_context(context)?)
+                    }
+                    None => Box::new(std::io::stdout().lock()),
+                };
+                if pack_output {
+                    let font = font.pack_with(input.parent().unwrap_or(Path::new("")));
+                    encode_bin_asset(&font, Some(&BinHeader::of::<BitmapFont>()), output_format, &mut output)?;
+                } else {
+                    encode_bin_asset(&font, None, output_format, &mut output)?;
+                }
+            }
+            Commands::Info(args) => args.run()?,
+            Commands::Lint(args) => args.run()?,
+            Commands::Scaffold(args) => args.run()?,
+            Commands::Diff(args) => args.run()?,
+            Commands::Schema(args) => args.run()?,
+            #[cfg(feature = "render")]
+            Commands::Render(args) => args.run()?,
+            Commands::Batch(args) => args.run()?,
+            Commands::List(args) => args.run()?,
+            Commands::Extract(args) => args.run()?,
         }
         Ok(())
     }
 }
 
+/// Load the resource manifest (`properties/resources.xml`) from the game directory.
+pub fn load_manifest(path: &Path) -> anyhow::Result<ResourceManifest> {
+    let src = std::fs::read_to_string(path).with_context(|| format!("failed to read file {path:?}"))?;
+    ResourceManifest::from_xml_str(&src).with_context(|| format!("failed to parse resource manifest {path:?}"))
+}
+
+/// Run some action with an [`ImageResolver`]: use the resource manifest if specified, and fall
+/// back to [`ReanimNameHeuristic`] otherwise. Image extensions are determined by probing the game
+/// directory, assuming the manifest is located at `properties/resources.xml`.
+pub fn with_resolver<T>(
+    manifest: Option<&Path>,
+    action: impl FnOnce(&(dyn ImageResolver + Sync)) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let Some(path) = manifest else { return action(&ReanimNameHeuristic) };
+    let manifest = load_manifest(path)?;
+    let game_dir = path.parent().and_then(Path::parent);
+    action(&ManifestResolver::new(&manifest, game_dir))
+}
+
+/// Decode the animation from a file in the given format.
+pub fn decode_anim(path: &Path, format: Format, packed: bool) -> anyhow::Result<MaybePacked> {
+    let input = File::open(path).with_context(|| format!("failed to read file {path:?}"))?;
+    let mut input = BufReader::new(input);
+    Ok(match format {
+        Internal => anyhow::bail!("unsupported input format: {format}"),
+        Xml => {
+            let mut src = String::new();
+            input.read_to_string(&mut src)?;
+            let anim = Animation::from_xml_str(&src)
+                .with_context(|| format!("failed to parse XML file {path:?}"))?;
+            Plain(anim)
+        }
+        Bin => {
+            let mut src = Vec::new();
+            input.read_to_end(&mut src)?;
+            Packed(decode_bin::<packed::Animation>(&src)?)
+        }
+        Compiled => Plain(Animation::decompress_and_decode(&mut input)?),
+        Json if packed => Packed(serde_json::from_reader(&mut input)?),
+        Yaml if packed => Packed(serde_yaml::from_reader(&mut input)?),
+        Json => Plain(serde_json::from_reader(&mut input)?),
+        Yaml => Plain(serde_yaml::from_reader(&mut input)?),
+    })
+}
+
+/// Decode the model from a file in the given format.
+pub fn decode_model(path: &Path, format: Format) -> anyhow::Result<model::Model> {
+    let input = File::open(path).with_context(|| format!("failed to read file {path:?}"))?;
+    let mut input = BufReader::new(input);
+    Ok(match format {
+        Internal | Compiled | Xml => anyhow::bail!("unsupported input format: {format}"),
+        Bin => {
+            let mut src = Vec::new();
+            input.read_to_end(&mut src)?;
+            decode_bin::<model::Model>(&src)?
+        }
+        Json => serde_json::from_reader(&mut input)?,
+        Yaml => serde_yaml::from_reader(&mut input)?,
+    })
+}
+
+/// Decode the particle definition from a file in the given format.
+pub fn decode_particles(path: &Path, format: Format) -> anyhow::Result<ParticleDefinition> {
+    let input = File::open(path).with_context(|| format!("failed to read file {path:?}"))?;
+    let mut input = BufReader::new(input);
+    Ok(match format {
+        Internal | Xml => anyhow::bail!("unsupported input format: {format}"),
+        Compiled => ParticleDefinition::decompress_and_decode(&mut input)?,
+        Bin => bincode::decode_from_std_read(&mut input, BINCODE_CONFIG)?,
+        Json => serde_json::from_reader(&mut input)?,
+        Yaml => serde_yaml::from_reader(&mut input)?,
+    })
+}
+
+/// Decode the trail definition from a file in the given format.
+pub fn decode_trail(path: &Path, format: Format) -> anyhow::Result<TrailDefinition> {
+    let input = File::open(path).with_context(|| format!("failed to read file {path:?}"))?;
+    let mut input = BufReader::new(input);
+    Ok(match format {
+        Internal | Xml => anyhow::bail!("unsupported input format: {format}"),
+        Compiled => TrailDefinition::decompress_and_decode(&mut input)?,
+        Bin => {
+            if input.fill_buf()?.starts_with(BIN_MAGIC.as_bytes()) {
+                anyhow::bail!("{path:?} is a packed trail, which cannot be converted back to a trail definition");
+            }
+            bincode::decode_from_std_read(&mut input, BINCODE_CONFIG)?
+        }
+        Json => serde_json::from_reader(&mut input)?,
+        Yaml => serde_yaml::from_reader(&mut input)?,
+    })
+}
+
+/// Decode the bitmap font definition from a font description script.
+pub fn decode_font(path: &Path) -> anyhow::Result<FontDefinition> {
+    let src = std::fs::read(path).with_context(|| format!("failed to read file {path:?}"))?;
+    // scripts are mostly ASCII, but might contain Latin-1 characters in the character lists
+    let src = String::from_utf8(src).unwrap_or_else(|err| {
+        tracing::debug!("{path:?} is not valid UTF-8, decoding as Latin-1");
+        err.into_bytes().into_iter().map(char::from).collect()
+    });
+    FontDefinition::from_script(&src).with_context(|| format!("failed to parse font description {path:?}"))
+}
+
 /// Encode the animation into required format.
-pub fn encode_anim(anim: MaybePacked, format: Format, mut output: impl Write) -> anyhow::Result<()> {
+/// Compression only applies to the compiled format.
+pub fn encode_anim(anim: MaybePacked, format: Format, compress: bool, mut output: impl Write) -> anyhow::Result<()> {
     match (format, anim) {
         (Internal, anim) => writeln!(output, "{anim:#?}")?,
-        (Bin, Packed(anim)) => {
-            bincode::encode_into_std_write(anim, &mut output, BINCODE_CONFIG)?;
-        }
+        (Compiled, Plain(anim)) => anim.encode_and_compress(&mut output, compress)?,
+        (Bin, Packed(anim)) => encode_bin::<packed::Animation, _>(&anim, &mut output)?,
         (Xml, Plain(anim)) => write!(output, "{}", XmlWrapper(anim))?,
         (Json, anim) => serde_json::to_writer_pretty(output, &anim)?,
         (Yaml, anim) => serde_yaml::to_writer(output, &anim)?,
@@ -314,9 +610,38 @@ pub fn encode_model(model: model::Model, format: Format, mut output: impl Write)
     match format {
         Compiled | Xml => anyhow::bail!("unsupported output format: '{format}'"),
         Internal => writeln!(output, "{model:#?}")?,
-        Bin => { bincode::encode_into_std_write(&model, &mut output, BINCODE_CONFIG)?; }
+        Bin => encode_bin::<model::Model, _>(&model, &mut output)?,
         Json => serde_json::to_writer_pretty(output, &model)?,
         Yaml => serde_yaml::to_writer(output, &model)?,
     }
     Ok(())
 }
+
+/// Encode the particle definition into required format.
+pub fn encode_particles(particles: ParticleDefinition, format: Format, mut output: impl Write) -> anyhow::Result<()> {
+    match format {
+        Compiled | Xml => anyhow::bail!("unsupported output format: '{format}'"),
+        Internal => writeln!(output, "{particles:#?}")?,
+        Bin => { bincode::encode_into_std_write(&particles, &mut output, BINCODE_CONFIG)?; }
+        Json => serde_json::to_writer_pretty(output, &particles)?,
+        Yaml => serde_yaml::to_writer(output, &particles)?,
+    }
+    Ok(())
+}
+
+/// Encode a definition (e.g., fonts and trails, plain or packed) into required format.
+/// Packed definitions are assets, and should be given a [`BinHeader`] for the `bincode` format.
+pub fn encode_bin_asset<T>(value: &T, header: Option<&BinHeader>, format: Format, mut output: impl Write) -> anyhow::Result<()>
+    where T: Debug + Serialize + bincode::Encode {
+    match format {
+        Compiled | Xml => anyhow::bail!("unsupported output format: '{format}'"),
+        Internal => writeln!(output, "{value:#?}")?,
+        Bin => match header {
+            Some(header) => encode_bin_with(header, value, &mut output)?,
+            None => { bincode::encode_into_std_write(value, &mut output, BINCODE_CONFIG)?; }
+        },
+        Json => serde_json::to_writer_pretty(output, value)?,
+        Yaml => serde_yaml::to_writer(output, value)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,343 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Batch conversion for whole directories of animations or models.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+use crate::reanim::ImageResolver;
+use super::{decode_anim, decode_model, encode_anim, encode_model, with_resolver, Format};
+use super::Format::*;
+
+/// Kind of files to convert in batch mode.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum FileKind {
+    /// Animation files.
+    Anim,
+    /// Model files, with a secondary extension `.model`.
+    Model,
+}
+
+/// Arguments for batch conversion.
+#[derive(Debug, Args)]
+pub struct BatchArgs {
+    /// Kind of files to convert.
+    #[clap(value_enum)]
+    pub kind: FileKind,
+    /// Input directory, searched recursively.
+    pub input: PathBuf,
+    /// Only convert files whose names match this pattern (wildcards '*' and '?' supported).
+    #[clap(short, long)]
+    pub pattern: Option<String>,
+    /// Input format. Inferred for each file from its extension by default.
+    #[clap(short = 'I', long, value_enum)]
+    pub input_format: Option<Format>,
+    /// Output directory, mirroring the structure of the input directory.
+    #[clap(short, long)]
+    pub output: PathBuf,
+    /// Output format.
+    #[clap(short = 'O', long, value_enum, default_value_t = Bin)]
+    pub output_format: Format,
+    /// Use structural format for output (always the case for 'bin').
+    #[clap(long)]
+    pub pack_output: bool,
+    /// Do not compress the output (only for the compiled format).
+    #[clap(long)]
+    pub uncompressed: bool,
+    /// Resolve image names using the resource manifest (`properties/resources.xml`).
+    #[clap(long)]
+    pub resources: Option<PathBuf>,
+    /// Convert all the files, even if the outputs are already up-to-date.
+    #[clap(short, long)]
+    pub force: bool,
+    /// Number of parallel jobs. Defaults to the available parallelism.
+    #[clap(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+}
+
+thread_local! {
+    static PACK_WARNINGS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Count warnings emitted with target `pack` on each thread, for reporting in batch mode.
+#[derive(Debug, Copy, Clone)]
+pub struct PackWarningCounter;
+
+impl PackWarningCounter {
+    /// Reset the counter for the current thread, and return its previous value.
+    pub fn take() -> usize { PACK_WARNINGS.with(|n| n.replace(0)) }
+}
+
+impl<S: Subscriber> Layer<S> for PackWarningCounter {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let meta = event.metadata();
+        if *meta.level() == Level::WARN && meta.target() == "pack" {
+            PACK_WARNINGS.with(|n| n.set(n.get() + 1));
+        }
+    }
+}
+
+struct Job {
+    input: PathBuf,
+    input_format: Format,
+    output: PathBuf,
+}
+
+enum Outcome {
+    Converted { warnings: usize },
+    UpToDate,
+    Failed(anyhow::Error),
+}
+
+impl BatchArgs {
+    /// Run the batch conversion, and print a summary.
+    pub fn run(self) -> anyhow::Result<()> {
+        let pack_output = self.pack_output || self.output_format == Bin;
+        let ext = output_extension(self.kind, self.output_format, pack_output)?;
+        let mut inputs = Vec::new();
+        collect_files(&self.input, &mut inputs)
+            .with_context(|| format!("failed to read directory {:?}", self.input))?;
+        inputs.sort();
+
+        let jobs = inputs.into_iter().filter_map(|input| {
+            let name = input.file_name()?.to_str()?;
+            if !self.kind.accepts(name) { return None; }
+            if let Some(pattern) = &self.pattern {
+                if !wildcard_match(pattern, name) { return None; }
+            }
+            let inferred = Format::infer(&input);
+            let input_format = match self.input_format {
+                Some(format) if inferred.is_none() || inferred == Some(format) => format,
+                Some(_) => return None,
+                None => inferred?,
+            };
+            let relative = input.strip_prefix(&self.input).ok()?;
+            let base_name = self.kind.strip_extension(name);
+            let output = self.output.join(relative).with_file_name(format!("{base_name}.{ext}"));
+            Some(Job { input, input_format, output })
+        }).collect::<Vec<_>>();
+        // parallel workers must never write to the same output file
+        let mut outputs = HashMap::with_capacity(jobs.len());
+        for job in &jobs {
+            if let Some(other) = outputs.insert(&job.output, &job.input) {
+                anyhow::bail!(
+                    "both {other:?} and {:?} would be converted to {:?}, use '--pattern' to select one of them",
+                    job.input, job.output,
+                );
+            }
+        }
+
+        let next = AtomicUsize::new(0);
+        let outcomes = Mutex::new(Vec::with_capacity(jobs.len()));
+        let workers = self.jobs.map_or_else(
+            || std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            NonZeroUsize::get,
+        ).min(jobs.len().max(1));
+        with_resolver(self.resources.as_deref(), |resolver| {
+            std::thread::scope(|scope| for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let k = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(k) else { break };
+                    let outcome = self.convert(job, pack_output, resolver);
+                    outcomes.lock().unwrap().push((k, outcome));
+                });
+            });
+            Ok(())
+        })?;
+        let mut outcomes = outcomes.into_inner().unwrap();
+        outcomes.sort_by_key(|(k, _)| *k);
+
+        let mut converted = 0_usize;
+        let mut up_to_date = 0_usize;
+        let mut with_warnings = Vec::new();
+        let mut failures = Vec::new();
+        for (k, outcome) in outcomes {
+            let input = jobs[k].input.strip_prefix(&self.input).unwrap_or(&jobs[k].input);
+            match outcome {
+                Outcome::Converted { warnings } => {
+                    converted += 1;
+                    if warnings > 0 { with_warnings.push((input, warnings)); }
+                }
+                Outcome::UpToDate => up_to_date += 1,
+                Outcome::Failed(err) => failures.push((input, err)),
+            }
+        }
+        println!(
+            "converted: {converted} ({} with warnings), up-to-date: {up_to_date}, failed: {}",
+            with_warnings.len(), failures.len(),
+        );
+        if !with_warnings.is_empty() {
+            println!("warnings:");
+            for (input, n) in with_warnings {
+                println!("  {}: {n} warning(s)", input.display());
+            }
+        }
+        if !failures.is_empty() {
+            println!("failures:");
+            for (input, err) in &failures {
+                println!("  {}: {err:#}", input.display());
+            }
+            anyhow::bail!("{} file(s) failed to convert", failures.len());
+        }
+        Ok(())
+    }
+
+    fn convert(&self, job: &Job, pack_output: bool, resolver: &(dyn ImageResolver + Sync)) -> Outcome {
+        let _span = tracing::error_span!("batch", file = %job.input.display()).entered();
+        if !self.force && is_up_to_date(&job.input, &job.output) {
+            return Outcome::UpToDate;
+        }
+        PackWarningCounter::take();
+        match self.convert_impl(job, pack_output, resolver) {
+            Ok(()) => Outcome::Converted { warnings: PackWarningCounter::take() },
+            Err(err) => {
+                // do not leave partial outputs behind, otherwise they would be "up-to-date"
+                let _ = fs::remove_file(&job.output);
+                Outcome::Failed(err)
+            }
+        }
+    }
+
+    fn convert_impl(&self, job: &Job, pack_output: bool, resolver: &(dyn ImageResolver + Sync)) -> anyhow::Result<()> {
+        if let Some(parent) = job.output.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create directory {parent:?}"))?;
+        }
+        let context = || format!("failed to open output file {:?}", job.output);
+        match self.kind {
+            FileKind::Anim => {
+                let pack_input = Format::infer_packed(&job.input);
+                let anim = decode_anim(&job.input, job.input_format, pack_input)?;
+                let anim = anim.into_packed(pack_output, resolver)?;
+                let output = BufWriter::new(File::create(&job.output).with_context(context)?);
+                encode_anim(anim, self.output_format, !self.uncompressed, output)
+            }
+            FileKind::Model => {
+                let model = decode_model(&job.input, job.input_format)?;
+                let output = BufWriter::new(File::create(&job.output).with_context(context)?);
+                encode_model(model, self.output_format, output)
+            }
+        }
+    }
+}
+
+impl FileKind {
+    /// Known extensions of input files, longer ones first.
+    fn input_extensions(self) -> &'static [&'static str] {
+        match self {
+            FileKind::Anim => &[
+                "reanim.compiled", "anim.bin", "anim.json", "anim.yaml", "anim.yml", "anim.txt",
+                "reanim", "compiled", "xml", "bin", "json", "yaml", "yml", "txt",
+            ],
+            FileKind::Model => &["model.bin", "model.json", "model.yaml", "model.yml", "model.txt"],
+        }
+    }
+
+    /// Strip the (possibly multi-part) input extension from a file name.
+    fn strip_extension(self, file_name: &str) -> &str {
+        self.input_extensions().iter()
+            .find_map(|ext| file_name.strip_suffix(ext)?.strip_suffix('.'))
+            .filter(|base_name| !base_name.is_empty())
+            .unwrap_or(file_name)
+    }
+
+    fn accepts(self, file_name: &str) -> bool {
+        let is_model = file_name.contains(".model.");
+        match self {
+            FileKind::Anim => !is_model,
+            FileKind::Model => is_model,
+        }
+    }
+}
+
+pub(super) fn output_extension(kind: FileKind, format: Format, packed: bool) -> anyhow::Result<&'static str> {
+    Ok(match (kind, format, packed) {
+        (FileKind::Model, Internal, _) => "model.txt",
+        (FileKind::Model, Bin, _) => "model.bin",
+        (FileKind::Model, Json, _) => "model.json",
+        (FileKind::Model, Yaml, _) => "model.yaml",
+        (FileKind::Anim, Internal, true) => "anim.txt",
+        (FileKind::Anim, Bin, true) => "anim.bin",
+        (FileKind::Anim, Json, true) => "anim.json",
+        (FileKind::Anim, Yaml, true) => "anim.yaml",
+        (FileKind::Anim, Internal, false) => "txt",
+        (FileKind::Anim, Compiled, false) => "reanim.compiled",
+        (FileKind::Anim, Xml, false) => "reanim",
+        (FileKind::Anim, Json, false) => "json",
+        (FileKind::Anim, Yaml, false) => "yaml",
+        _ => anyhow::bail!("format '{format}' does not support 'packed={packed}'"),
+    })
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_up_to_date(input: &Path, output: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    matches!((modified(input), modified(output)), (Some(input), Some(output)) if output >= input)
+}
+
+/// Match a file name against a pattern, where '*' matches any sequence of characters (possibly
+/// empty), and '?' matches exactly one character.
+pub(super) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // position of the last '*' in the pattern, and the name position it is matched against
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
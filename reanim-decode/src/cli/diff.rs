@@ -0,0 +1,292 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Structural difference between two animations.
+
+use std::fmt::Debug;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use clap::Args;
+use serde::Serialize;
+use libre_pvz_resources::animation::{Action, AnimDesc, Frame, Track};
+use super::{decode_anim, with_resolver, Format, MaybePacked};
+
+/// Arguments for comparing two animations.
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// The old animation.
+    pub old: PathBuf,
+    /// The new animation.
+    pub new: PathBuf,
+    /// Input format (for both animations).
+    #[clap(short = 'I', long, value_enum)]
+    pub input_format: Option<Format>,
+    /// Use structural format for input.
+    #[clap(long)]
+    pub pack_input: bool,
+    /// Tolerance for comparing floating point numbers.
+    #[clap(long, default_value_t = 1e-4)]
+    pub tolerance: f32,
+    /// Resolve image names using the resource manifest (`properties/resources.xml`).
+    #[clap(long)]
+    pub resources: Option<PathBuf>,
+    /// Print the difference as JSON.
+    #[clap(long)]
+    pub json: bool,
+}
+
+/// A change in some value.
+#[derive(Debug, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change<T> {
+    /// The value is added in the new animation.
+    Added {
+        /// The new value.
+        new: T,
+    },
+    /// The value is removed in the new animation.
+    Removed {
+        /// The old value.
+        old: T,
+    },
+    /// The value is changed.
+    Changed {
+        /// The old value.
+        old: T,
+        /// The new value.
+        new: T,
+    },
+}
+
+/// Difference between two animations.
+#[derive(Debug, Default, Serialize)]
+pub struct AnimDiff {
+    /// Change in frames per second.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fps: Option<Change<f32>>,
+    /// Changes in meta data: (inclusive) frame ranges.
+    pub meta: Vec<Named<Change<[u16; 2]>>>,
+    /// Changes in tracks.
+    pub tracks: Vec<Named<TrackDiff>>,
+}
+
+/// Something with a name, e.g., a meta or a track.
+#[derive(Debug, Serialize)]
+pub struct Named<T> {
+    /// The name.
+    pub name: String,
+    /// The named content.
+    #[serde(flatten)]
+    pub content: T,
+}
+
+/// Difference in a track.
+#[derive(Debug, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum TrackDiff {
+    /// The track is added in the new animation.
+    Added {
+        /// Number of frames in the new track.
+        frame_count: usize,
+    },
+    /// The track is removed in the new animation.
+    Removed {
+        /// Number of frames in the old track.
+        frame_count: usize,
+    },
+    /// Some frames are changed.
+    Changed {
+        /// Change in the number of frames.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        frame_count: Option<[usize; 2]>,
+        /// Frames with changed actions.
+        frames: Vec<FrameDiff>,
+    },
+}
+
+/// Difference in a single frame.
+#[derive(Debug, Serialize)]
+pub struct FrameDiff {
+    /// Index of this frame.
+    pub frame: usize,
+    /// Actions in the old frame.
+    pub old: Vec<Action>,
+    /// Actions in the new frame.
+    pub new: Vec<Action>,
+}
+
+fn float_eq(x: f32, y: f32, tolerance: f32) -> bool {
+    x == y || (x - y).abs() <= tolerance
+}
+
+fn vec_eq(x: [f32; 2], y: [f32; 2], tolerance: f32) -> bool {
+    float_eq(x[0], y[0], tolerance) && float_eq(x[1], y[1], tolerance)
+}
+
+fn action_eq(x: &Action, y: &Action, tolerance: f32) -> bool {
+    match (x, y) {
+        (Action::LoadElement(x), Action::LoadElement(y)) => x == y,
+        (Action::Alpha(x), Action::Alpha(y)) => float_eq(*x, *y, tolerance),
+        (Action::Show(x), Action::Show(y)) => x == y,
+        (Action::Translation(x), Action::Translation(y))
+        | (Action::Scale(x), Action::Scale(y))
+        | (Action::Rotation(x), Action::Rotation(y)) => vec_eq(*x, *y, tolerance),
+        _ => false,
+    }
+}
+
+fn frame_eq(x: &[Action], y: &[Action], tolerance: f32) -> bool {
+    x.len() == y.len() && std::iter::zip(x, y).all(|(x, y)| action_eq(x, y, tolerance))
+}
+
+fn frame_actions(frames: &[Frame], k: usize) -> &[Action] {
+    frames.get(k).map_or(&[], |frame| &frame.0)
+}
+
+fn diff_track(old: &Track, new: &Track, tolerance: f32) -> Option<TrackDiff> {
+    let frames = (0..old.frames.len().max(new.frames.len()))
+        .filter_map(|k| {
+            let (old, new) = (frame_actions(&old.frames, k), frame_actions(&new.frames, k));
+            (!frame_eq(old, new, tolerance)).then(|| FrameDiff { frame: k, old: old.to_vec(), new: new.to_vec() })
+        })
+        .collect::<Vec<_>>();
+    let frame_count = (old.frames.len() != new.frames.len()).then_some([old.frames.len(), new.frames.len()]);
+    (frame_count.is_some() || !frames.is_empty()).then_some(TrackDiff::Changed { frame_count, frames })
+}
+
+/// Compare two animations. Tracks are matched by their names (and then their order, if there are
+/// multiple tracks with the same name).
+pub fn diff_anim(old: &AnimDesc, new: &AnimDesc, tolerance: f32) -> AnimDiff {
+    let mut diff = AnimDiff::default();
+    if !float_eq(old.fps, new.fps, tolerance) {
+        diff.fps = Some(Change::Changed { old: old.fps, new: new.fps });
+    }
+
+    for meta in old.meta.iter() {
+        let old_range = [meta.start_frame, meta.end_frame];
+        let change = match new.get_meta(&meta.name) {
+            None => Change::Removed { old: old_range },
+            Some((_, new)) if [new.start_frame, new.end_frame] != old_range =>
+                Change::Changed { old: old_range, new: [new.start_frame, new.end_frame] },
+            Some(_) => continue,
+        };
+        diff.meta.push(Named { name: meta.name.clone(), content: change });
+    }
+    for meta in new.meta.iter().filter(|meta| old.get_meta(&meta.name).is_none()) {
+        let change = Change::Added { new: [meta.start_frame, meta.end_frame] };
+        diff.meta.push(Named { name: meta.name.clone(), content: change });
+    }
+
+    let mut matched = vec![false; new.tracks.len()];
+    for old_track in old.tracks.iter() {
+        let found = new.tracks.iter().enumerate()
+            .find(|(k, track)| !matched[*k] && track.name == old_track.name);
+        let change = match found {
+            None => Some(TrackDiff::Removed { frame_count: old_track.frames.len() }),
+            Some((k, new_track)) => {
+                matched[k] = true;
+                diff_track(old_track, new_track, tolerance)
+            }
+        };
+        if let Some(change) = change {
+            diff.tracks.push(Named { name: old_track.name.clone(), content: change });
+        }
+    }
+    for (new_track, _) in std::iter::zip(new.tracks.iter(), matched).filter(|(_, matched)| !matched) {
+        let change = TrackDiff::Added { frame_count: new_track.frames.len() };
+        diff.tracks.push(Named { name: new_track.name.clone(), content: change });
+    }
+    diff
+}
+
+fn print_change<T: Debug>(output: &mut impl Write, what: &str, change: &Change<T>) -> std::io::Result<()> {
+    match change {
+        Change::Added { new } => writeln!(output, "+ {what}: {new:?}"),
+        Change::Removed { old } => writeln!(output, "- {what}: {old:?}"),
+        Change::Changed { old, new } => writeln!(output, "~ {what}: {old:?} -> {new:?}"),
+    }
+}
+
+impl AnimDiff {
+    /// Are the two animations the same?
+    pub fn is_empty(&self) -> bool {
+        self.fps.is_none() && self.meta.is_empty() && self.tracks.is_empty()
+    }
+
+    /// Print the difference in a human-readable form.
+    pub fn print(&self, mut output: impl Write) -> std::io::Result<()> {
+        if let Some(fps) = &self.fps {
+            print_change(&mut output, "fps", fps)?;
+        }
+        for meta in &self.meta {
+            print_change(&mut output, &format!("meta '{}'", meta.name), &meta.content)?;
+        }
+        for track in &self.tracks {
+            match &track.content {
+                TrackDiff::Added { frame_count } =>
+                    writeln!(output, "+ track '{}' ({frame_count} frames)", track.name)?,
+                TrackDiff::Removed { frame_count } =>
+                    writeln!(output, "- track '{}' ({frame_count} frames)", track.name)?,
+                TrackDiff::Changed { frame_count, frames } => {
+                    write!(output, "~ track '{}'", track.name)?;
+                    match frame_count {
+                        Some([old, new]) => writeln!(output, " ({old} -> {new} frames)")?,
+                        None => writeln!(output)?,
+                    }
+                    for frame in frames {
+                        writeln!(output, "    frame {}:", frame.frame)?;
+                        writeln!(output, "      - {:?}", frame.old)?;
+                        writeln!(output, "      + {:?}", frame.new)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DiffArgs {
+    fn load(&self, path: &Path) -> anyhow::Result<MaybePacked> {
+        let pack_input = self.pack_input || Format::infer_packed(path);
+        let input_format = Format::decide(self.input_format, Some(path), Format::Compiled);
+        decode_anim(path, input_format, pack_input)
+    }
+
+    /// Compare the animations, and print the difference.
+    pub fn run(self) -> anyhow::Result<()> {
+        let (old, new) = (self.load(&self.old)?, self.load(&self.new)?);
+        let (old, new) = with_resolver(self.resources.as_deref(), |resolver| {
+            Ok((old.into_packed(true, resolver)?, new.into_packed(true, resolver)?))
+        })?;
+        let (MaybePacked::Packed(old), MaybePacked::Packed(new)) = (old, new) else {
+            unreachable!("animations should be packed")
+        };
+
+        let diff = diff_anim(&old, &new, self.tolerance);
+        let mut stdout = std::io::stdout().lock();
+        if self.json {
+            serde_json::to_writer_pretty(&mut stdout, &diff)?;
+            writeln!(stdout)?;
+        } else if diff.is_empty() {
+            tracing::info!("no difference found");
+        } else {
+            diff.print(stdout)?;
+        }
+        Ok(())
+    }
+}
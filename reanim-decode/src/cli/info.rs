@@ -0,0 +1,184 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Summary of an animation: meta data, tracks, and referenced images.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::PathBuf;
+use clap::Args;
+use serde::Serialize;
+use libre_pvz_resources::animation::{Action, AnimDesc, Track};
+use super::{decode_anim, with_resolver, Format, MaybePacked};
+
+/// Arguments for summarizing an animation.
+#[derive(Debug, Args)]
+pub struct InfoArgs {
+    /// Input file path.
+    pub input: PathBuf,
+    /// Input format.
+    #[clap(short = 'I', long, value_enum)]
+    pub input_format: Option<Format>,
+    /// Use structural format for input.
+    #[clap(long)]
+    pub pack_input: bool,
+    /// Resolve image names using the resource manifest (`properties/resources.xml`).
+    #[clap(long)]
+    pub resources: Option<PathBuf>,
+    /// Print the summary as JSON.
+    #[clap(long)]
+    pub json: bool,
+}
+
+/// Summary of an animation.
+#[derive(Debug, Serialize)]
+pub struct AnimInfo {
+    /// Frames per second.
+    pub fps: f32,
+    /// Number of frames (of the longest track).
+    pub frame_count: usize,
+    /// Meta data, with frame ranges.
+    pub meta: Vec<MetaInfo>,
+    /// Tracks, with key frame statistics.
+    pub tracks: Vec<TrackInfo>,
+    /// All images referenced in this animation, deduplicated.
+    pub images: BTreeSet<PathBuf>,
+}
+
+/// Summary of a [`Meta`](libre_pvz_resources::animation::Meta).
+#[derive(Debug, Serialize)]
+pub struct MetaInfo {
+    /// Name of this meta data.
+    pub name: String,
+    /// (inclusive) Start frame.
+    pub start_frame: u16,
+    /// (inclusive) End frame.
+    pub end_frame: u16,
+}
+
+/// Summary of a [`Track`].
+#[derive(Debug, Default, Serialize)]
+pub struct TrackInfo {
+    /// Track name.
+    pub name: String,
+    /// Number of frames with at least one action.
+    pub key_frames: usize,
+    /// Number of [`Action::LoadElement`].
+    pub load_element: usize,
+    /// Number of [`Action::Alpha`].
+    pub alpha: usize,
+    /// Number of [`Action::Show`].
+    pub show: usize,
+    /// Number of [`Action::Translation`].
+    pub translation: usize,
+    /// Number of [`Action::Scale`].
+    pub scale: usize,
+    /// Number of [`Action::Rotation`].
+    pub rotation: usize,
+}
+
+impl TrackInfo {
+    /// Collect key frame statistics for a track.
+    pub fn new(track: &Track) -> TrackInfo {
+        let mut info = TrackInfo { name: track.name.clone(), ..TrackInfo::default() };
+        for frame in track.frames.iter() {
+            if !frame.0.is_empty() { info.key_frames += 1; }
+            for action in frame.0.iter() {
+                *match action {
+                    Action::LoadElement(_) => &mut info.load_element,
+                    Action::Alpha(_) => &mut info.alpha,
+                    Action::Show(_) => &mut info.show,
+                    Action::Translation(_) => &mut info.translation,
+                    Action::Scale(_) => &mut info.scale,
+                    Action::Rotation(_) => &mut info.rotation,
+                } += 1;
+            }
+        }
+        info
+    }
+}
+
+impl AnimInfo {
+    /// Collect the summary for an animation.
+    pub fn new(anim: &AnimDesc) -> AnimInfo {
+        AnimInfo {
+            fps: anim.fps,
+            frame_count: anim.tracks.iter().map(|track| track.frames.len()).max().unwrap_or(0),
+            meta: anim.meta.iter().map(|meta| MetaInfo {
+                name: meta.name.clone(),
+                start_frame: meta.start_frame,
+                end_frame: meta.end_frame,
+            }).collect(),
+            tracks: anim.tracks.iter().map(TrackInfo::new).collect(),
+            images: anim.image_files().map(|image| image.raw_key.clone()).collect(),
+        }
+    }
+
+    /// Print the summary as human-readable tables.
+    pub fn print(&self, mut output: impl Write) -> std::io::Result<()> {
+        writeln!(output, "fps: {}, frames: {}", self.fps, self.frame_count)?;
+
+        writeln!(output, "\nmeta ({}):", self.meta.len())?;
+        let width = self.meta.iter().map(|meta| meta.name.len()).max().unwrap_or(0).max(4);
+        writeln!(output, "  {:width$}  {:>5}  {:>5}", "name", "start", "end")?;
+        for meta in &self.meta {
+            writeln!(output, "  {:width$}  {:>5}  {:>5}", meta.name, meta.start_frame, meta.end_frame)?;
+        }
+
+        writeln!(output, "\ntracks ({}):", self.tracks.len())?;
+        let width = self.tracks.iter().map(|track| track.name.len()).max().unwrap_or(0).max(4);
+        writeln!(
+            output, "  {:width$}  {:>5}  {:>7}  {:>5}  {:>5}  {:>5}  {:>5}  {:>5}",
+            "name", "keys", "element", "alpha", "show", "trans", "scale", "rot",
+        )?;
+        for track in &self.tracks {
+            writeln!(
+                output, "  {:width$}  {:>5}  {:>7}  {:>5}  {:>5}  {:>5}  {:>5}  {:>5}",
+                track.name, track.key_frames, track.load_element, track.alpha,
+                track.show, track.translation, track.scale, track.rotation,
+            )?;
+        }
+
+        writeln!(output, "\nimages ({}):", self.images.len())?;
+        for image in &self.images {
+            writeln!(output, "  {}", image.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl InfoArgs {
+    /// Print the summary of the animation.
+    pub fn run(self) -> anyhow::Result<()> {
+        let pack_input = self.pack_input || Format::infer_packed(&self.input);
+        let input_format = Format::decide(self.input_format, Some(&self.input), Format::Compiled);
+        let anim = decode_anim(&self.input, input_format, pack_input)?;
+        let anim = with_resolver(self.resources.as_deref(), |resolver| anim.into_packed(true, resolver))?;
+        let MaybePacked::Packed(anim) = anim else { unreachable!("animation should be packed") };
+
+        let info = AnimInfo::new(&anim);
+        let mut stdout = std::io::stdout().lock();
+        if self.json {
+            serde_json::to_writer_pretty(&mut stdout, &info)?;
+            writeln!(stdout)?;
+        } else {
+            info.print(stdout)?;
+        }
+        Ok(())
+    }
+}
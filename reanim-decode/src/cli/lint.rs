@@ -0,0 +1,249 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Offline validation of models against their animations.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use clap::Args;
+use libre_pvz_resources::animation::AnimDesc;
+use libre_pvz_resources::cached::ContainerWithKey;
+use libre_pvz_resources::model::Model;
+use crate::reanim::ReanimNameHeuristic;
+use super::{decode_anim, decode_model, Format, MaybePacked};
+
+/// Marker components registered by `libre_pvz_resources` itself.
+const BUILTIN_MARKERS: &[&str] = &["AutoNullTrigger"];
+
+/// Arguments for validating a model.
+#[derive(Debug, Args)]
+pub struct LintArgs {
+    /// Model file path.
+    pub model: PathBuf,
+    /// Asset directory against which paths in models are resolved (defaults to the directory of
+    /// the model file). Source files (`.json`, `.yaml`) are used in place of missing `.bin` files.
+    #[clap(long)]
+    pub assets: Option<PathBuf>,
+    /// Marker components registered in the game. Markers are not checked if none is specified.
+    #[clap(short, long = "marker")]
+    pub markers: Vec<String>,
+}
+
+/// A problem found in a model.
+#[derive(Debug)]
+pub struct Problem {
+    /// The model file in which the problem is found.
+    pub file: PathBuf,
+    /// Location of the problematic field in that file.
+    pub field: String,
+    /// Description of the problem.
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.file.display(), self.field, self.message)
+    }
+}
+
+struct Linter<'a> {
+    assets: &'a Path,
+    markers: Option<HashSet<&'a str>>,
+    visited: HashSet<PathBuf>,
+    problems: Vec<Problem>,
+}
+
+/// Trigger as displayed in field locations: quoted name, or `null`.
+fn trigger_name(trigger: &Option<String>) -> String {
+    match trigger {
+        Some(trigger) => format!("{trigger:?}"),
+        None => "null".to_string(),
+    }
+}
+
+impl Linter<'_> {
+    fn report(&mut self, file: &Path, field: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            file: file.to_path_buf(),
+            field: field.into(),
+            message: message.into(),
+        })
+    }
+
+    /// Resolve a path referenced in a model, falling back to the source files.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let path = self.assets.join(path);
+        if path.exists() { return Some(path); }
+        ["bin", "json", "yaml"].into_iter()
+            .map(|ext| path.with_extension(ext))
+            .find(|path| path.exists())
+    }
+
+    fn load_anim(&mut self, file: &Path, raw_path: &Path) -> Option<AnimDesc> {
+        let Some(path) = self.resolve(raw_path) else {
+            self.report(file, "animation", format!("animation {raw_path:?} not found"));
+            return None;
+        };
+        let format = Format::decide(None, Some(&path), Format::Compiled);
+        let anim = decode_anim(&path, format, Format::infer_packed(&path))
+            .and_then(|anim| anim.into_packed(true, &ReanimNameHeuristic));
+        match anim {
+            Ok(MaybePacked::Packed(anim)) => Some(anim),
+            Ok(MaybePacked::Plain(_)) => unreachable!("animation should be packed"),
+            Err(err) => {
+                self.report(file, "animation", format!("failed to load animation {path:?}: {err:#}"));
+                None
+            }
+        }
+    }
+
+    fn lint_model(&mut self, file: &Path) {
+        if !self.visited.insert(file.to_path_buf()) { return; }
+        let format = Format::decide(None, Some(file), Format::Bin);
+        let model = match decode_model(file, format) {
+            Ok(model) => model,
+            Err(err) => return self.report(file, "(root)", format!("{err:#}")),
+        };
+        let anim = self.load_anim(file, &model.animation.raw_key);
+        self.lint_markers(file, &model);
+        self.lint_states(file, &model, anim.as_ref());
+        self.lint_tracks(file, &model, anim.as_ref());
+        for attachment in model.attachments.iter() {
+            let raw_path = &attachment.child_model.raw_key;
+            match self.resolve(raw_path) {
+                Some(child) => self.lint_model(&child),
+                None => self.report(
+                    file, format!("attachments[{:?}].child_model", attachment.target_track),
+                    format!("model {raw_path:?} not found"),
+                ),
+            }
+        }
+    }
+
+    fn lint_markers(&mut self, file: &Path, model: &Model) {
+        let Some(markers) = &self.markers else { return };
+        let unknown = model.markers.iter().enumerate()
+            .filter(|(_, marker)| !markers.contains(marker.as_str()))
+            .map(|(k, marker)| (format!("markers[{k}]"), format!("unregistered marker '{marker}'")))
+            .collect::<Vec<_>>();
+        for (field, message) in unknown { self.report(file, field, message); }
+    }
+
+    fn lint_states(&mut self, file: &Path, model: &Model, anim: Option<&AnimDesc>) {
+        for pair in model.states.windows(2) {
+            if pair[0].name == pair[1].name {
+                self.report(file, format!("states[{:?}]", pair[1].name), "duplicate state");
+            }
+        }
+        let default_state = &model.default_state.raw_key;
+        let default = model.states.get_by_key(default_state);
+        if default.is_none() {
+            self.report(file, "default_state", format!("unknown state '{default_state}'"));
+        }
+
+        for state in model.states.iter() {
+            let field = format!("states[{:?}]", state.name);
+            let meta = &state.state_meta.raw_key;
+            if anim.is_some_and(|anim| anim.get_meta(meta).is_none()) {
+                self.report(file, format!("{field}.state_meta"), format!("unknown meta '{meta}'"));
+            }
+            for pair in state.transitions.windows(2) {
+                if pair[0].trigger == pair[1].trigger {
+                    let trigger = trigger_name(&pair[1].trigger);
+                    self.report(file, format!("{field}.transitions[{trigger}]"), "duplicate trigger");
+                }
+            }
+            for transition in state.transitions.iter() {
+                let dest = &transition.dest.raw_key;
+                if model.states.get_by_key(dest).is_none() {
+                    let trigger = trigger_name(&transition.trigger);
+                    self.report(
+                        file, format!("{field}.transitions[{trigger}].dest"),
+                        format!("dangling destination '{dest}'"),
+                    );
+                }
+            }
+        }
+
+        // unreachable states are only meaningful when the start-up state is known
+        let Some(default) = default else { return };
+        let mut reachable = vec![false; model.states.len()];
+        let mut queue = VecDeque::from([default]);
+        reachable[default] = true;
+        while let Some(k) = queue.pop_front() {
+            for transition in model.states[k].transitions.iter() {
+                let Some(dest) = model.states.get_by_key(&transition.dest.raw_key) else { continue };
+                if !reachable[dest] {
+                    reachable[dest] = true;
+                    queue.push_back(dest);
+                }
+            }
+        }
+        for (state, _) in model.states.iter().zip(reachable).filter(|(_, reachable)| !reachable) {
+            self.report(
+                file, format!("states[{:?}]", state.name),
+                format!("unreachable from the default state '{default_state}'"),
+            );
+        }
+    }
+
+    fn lint_tracks(&mut self, file: &Path, model: &Model, anim: Option<&AnimDesc>) {
+        let Some(anim) = anim else { return };
+        let has_track = |name: &str| anim.tracks.iter().any(|track| track.name == name);
+        for attachment in model.attachments.iter() {
+            let track = &attachment.target_track;
+            if !has_track(track) {
+                self.report(
+                    file, format!("attachments[{track:?}].target_track"),
+                    format!("missing track '{track}' in the animation"),
+                );
+            }
+        }
+        for (k, track) in model.hidden_tracks.iter().enumerate() {
+            if !has_track(track) {
+                self.report(
+                    file, format!("hidden_tracks[{k}]"),
+                    format!("missing track '{track}' in the animation"),
+                );
+            }
+        }
+    }
+}
+
+impl LintArgs {
+    /// Validate the model, its animation, and all its child models.
+    pub fn run(self) -> anyhow::Result<()> {
+        let assets = match &self.assets {
+            Some(assets) => assets.as_path(),
+            None => self.model.parent().unwrap_or(Path::new("")),
+        };
+        let markers = (!self.markers.is_empty()).then(|| BUILTIN_MARKERS.iter().copied()
+            .chain(self.markers.iter().map(String::as_str))
+            .collect());
+        let mut linter = Linter { assets, markers, visited: HashSet::new(), problems: Vec::new() };
+        linter.lint_model(&self.model);
+        for problem in &linter.problems {
+            println!("{problem}");
+        }
+        match linter.problems.len() {
+            0 => Ok(()),
+            n => anyhow::bail!("found {n} problem(s) in model {:?}", self.model),
+        }
+    }
+}
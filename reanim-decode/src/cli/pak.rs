@@ -0,0 +1,134 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Listing and extracting files in the package file `main.pak`.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use anyhow::Context;
+use clap::Args;
+use libre_pvz_resources::pak::{PakArchive, PakEntry};
+use crate::reanim::Animation;
+use super::batch::{output_extension, wildcard_match, FileKind};
+use super::{encode_anim, with_resolver, Format, MaybePacked};
+
+/// Arguments for listing files in a package.
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    /// Package file path, e.g., `main.pak`.
+    pub archive: PathBuf,
+    /// Only list files whose paths match this pattern (wildcards '*' and '?' supported).
+    #[clap(short, long)]
+    pub pattern: Option<String>,
+}
+
+/// Arguments for extracting files from a package.
+#[derive(Debug, Args)]
+pub struct ExtractArgs {
+    /// Package file path, e.g., `main.pak`.
+    pub archive: PathBuf,
+    /// Only extract files whose paths match this pattern (wildcards '*' and '?' supported).
+    #[clap(short, long)]
+    pub pattern: Option<String>,
+    /// Output directory, mirroring the structure inside the package.
+    #[clap(short, long)]
+    pub output: PathBuf,
+    /// Convert the animations (`.reanim.compiled`) to this format on the fly.
+    #[clap(short = 'O', long, value_enum)]
+    pub convert: Option<Format>,
+    /// Use structural format for converted animations (always the case for 'bin').
+    #[clap(long)]
+    pub pack_output: bool,
+    /// Resolve image names using the resource manifest (`properties/resources.xml`).
+    #[clap(long)]
+    pub resources: Option<PathBuf>,
+}
+
+fn open_archive(path: &Path) -> anyhow::Result<PakArchive<BufReader<File>>> {
+    let file = File::open(path).with_context(|| format!("failed to read file {path:?}"))?;
+    PakArchive::open(BufReader::new(file)).with_context(|| format!("failed to decode package {path:?}"))
+}
+
+fn matches(pattern: &Option<String>, entry: &PakEntry) -> bool {
+    match pattern {
+        Some(pattern) => wildcard_match(pattern, &entry.name),
+        None => true,
+    }
+}
+
+impl ListArgs {
+    /// List the files, with their sizes.
+    pub fn run(self) -> anyhow::Result<()> {
+        let archive = open_archive(&self.archive)?;
+        let mut stdout = std::io::stdout().lock();
+        for entry in archive.entries().iter().filter(|entry| matches(&self.pattern, entry)) {
+            writeln!(stdout, "{:>10}  {}", entry.size, entry.name)?;
+        }
+        Ok(())
+    }
+}
+
+const COMPILED_ANIM_SUFFIX: &str = ".reanim.compiled";
+
+impl ExtractArgs {
+    /// Extract the files, converting the animations if requested.
+    pub fn run(self) -> anyhow::Result<()> {
+        let mut archive = open_archive(&self.archive)?;
+        let pack_output = self.pack_output || self.convert == Some(Format::Bin);
+        let anim_ext = self.convert
+            .map(|format| output_extension(FileKind::Anim, format, pack_output))
+            .transpose()?;
+        let entries = archive.entries().iter()
+            .filter(|entry| matches(&self.pattern, entry))
+            .cloned().collect::<Vec<_>>();
+        with_resolver(self.resources.as_deref(), |resolver| {
+            for entry in &entries {
+                let _span = tracing::error_span!("extract", file = entry.name).entered();
+                if !Path::new(&entry.name).components().all(|c| matches!(c, Component::Normal(_))) {
+                    tracing::warn!("skipped file with suspicious path");
+                    continue;
+                }
+                let data = archive.read_entry(entry)
+                    .with_context(|| format!("failed to read '{}' from package", entry.name))?;
+                let mut output = self.output.join(&entry.name);
+                if let Some(parent) = output.parent() {
+                    fs::create_dir_all(parent).with_context(|| format!("failed to create directory {parent:?}"))?;
+                }
+                let context = |output: &Path| format!("failed to open output file {output:?}");
+                match (self.convert, anim_ext, entry.name.strip_suffix(COMPILED_ANIM_SUFFIX)) {
+                    (Some(format), Some(ext), Some(base)) => {
+                        output = self.output.join(format!("{base}.{ext}"));
+                        let anim = Animation::decompress_and_decode(&mut data.as_slice())
+                            .with_context(|| format!("failed to decode animation '{}'", entry.name))?;
+                        let anim = MaybePacked::Plain(anim).into_packed(pack_output, resolver)?;
+                        let file = File::create(&output).with_context(|| context(&output))?;
+                        encode_anim(anim, format, true, BufWriter::new(file))?;
+                    }
+                    _ => fs::write(&output, &data).with_context(|| context(&output))?,
+                }
+                if let Some(modified) = entry.modified() {
+                    let file = File::options().write(true).open(&output)?;
+                    file.set_modified(modified)?;
+                }
+                tracing::info!("extracted to {output:?}");
+            }
+            Ok(())
+        })
+    }
+}
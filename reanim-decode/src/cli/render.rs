@@ -0,0 +1,101 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Rendering animations to images.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use clap::Args;
+use crate::render::{encode_gif, sprite_sheet, RenderError, Renderer};
+use super::{decode_anim, with_resolver, Format, MaybePacked};
+
+/// Arguments for rendering an animation.
+#[derive(Debug, Args)]
+pub struct RenderArgs {
+    /// Input animation file path.
+    pub input: PathBuf,
+    /// Input format.
+    #[clap(short = 'I', long, value_enum)]
+    pub input_format: Option<Format>,
+    /// Use structural format for input.
+    #[clap(long)]
+    pub pack_input: bool,
+    /// Resolve image names using the resource manifest (`properties/resources.xml`).
+    #[clap(long)]
+    pub resources: Option<PathBuf>,
+    /// Asset directory against which image paths are resolved (defaults to the directory of the
+    /// resource manifest, or else the current directory).
+    #[clap(long)]
+    pub assets: Option<PathBuf>,
+    /// Render only the frames in this meta (defaults to the whole animation).
+    #[clap(short, long)]
+    pub meta: Option<String>,
+    /// Render a single frame (relative to the start of the meta, if specified).
+    #[clap(short, long)]
+    pub frame: Option<usize>,
+    /// Number of columns in the sprite sheet (defaults to a roughly square sheet).
+    #[clap(long)]
+    pub columns: Option<u32>,
+    /// Output file path. Animated GIF is generated for `.gif`, otherwise a single frame or a
+    /// sprite sheet is generated, in the format indicated by the extension.
+    #[clap(short, long)]
+    pub output: PathBuf,
+}
+
+fn is_gif(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
+}
+
+impl RenderArgs {
+    /// Render the animation.
+    pub fn run(self) -> anyhow::Result<()> {
+        let pack_input = self.pack_input || Format::infer_packed(&self.input);
+        let input_format = Format::decide(self.input_format, Some(&self.input), Format::Compiled);
+        let anim = decode_anim(&self.input, input_format, pack_input)?;
+        let anim = with_resolver(self.resources.as_deref(), |resolver| anim.into_packed(true, resolver))?;
+        let MaybePacked::Packed(anim) = anim else { unreachable!("animation should be packed") };
+
+        // 'properties/resources.xml' lives in the root of the asset directory
+        let assets = self.assets.as_deref()
+            .or_else(|| self.resources.as_deref()?.parent()?.parent())
+            .unwrap_or(Path::new(""));
+        let renderer = Renderer::new(&anim, assets)?;
+        let mut frames = renderer.frame_range(self.meta.as_deref())?;
+        if let Some(k) = self.frame {
+            // never fall through to the frames of another meta
+            let count = frames.end() + 1 - frames.start();
+            if k >= count { return Err(RenderError::FrameOutOfRange(k, count).into()); }
+            let k = frames.start() + k;
+            frames = k..=k;
+        }
+        let images = renderer.render_frames(frames)?;
+        tracing::info!("rendered {} frame(s)", images.len());
+
+        let context = || format!("failed to write output file {:?}", self.output);
+        if is_gif(&self.output) {
+            let output = File::create(&self.output).with_context(context)?;
+            encode_gif(images, anim.fps, BufWriter::new(output)).with_context(context)?;
+        } else {
+            let columns = self.columns.unwrap_or_else(|| (images.len() as f64).sqrt().ceil() as u32);
+            sprite_sheet(&images, columns).save(&self.output).with_context(context)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Scaffolding starter models from animations.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Context;
+use clap::Args;
+use libre_pvz_resources::animation::AnimDesc;
+use libre_pvz_resources::cached::Cached;
+use libre_pvz_resources::model::{Attachment, Model, State};
+use crate::reanim::ReanimNameHeuristic;
+use super::{decode_anim, encode_model, Format, MaybePacked};
+
+/// Prefix for meta tracks in the original game.
+const META_PREFIX: &str = "anim_";
+/// Name of the meta used for the default state, if present.
+const IDLE_META: &str = "anim_idle";
+/// Track names (without [`META_PREFIX`] and trailing digits) suggesting a separate part.
+const PART_NAMES: &[&str] = &["stem", "head", "body"];
+
+/// Arguments for scaffolding a model.
+#[derive(Debug, Args)]
+pub struct ScaffoldArgs {
+    /// Input animation file path.
+    pub input: PathBuf,
+    /// Input format.
+    #[clap(short = 'I', long, value_enum)]
+    pub input_format: Option<Format>,
+    /// Use structural format for input.
+    #[clap(long)]
+    pub pack_input: bool,
+    /// Animation path referenced in the model (defaults to `<name>.anim.bin`).
+    #[clap(long)]
+    pub animation: Option<PathBuf>,
+    /// Output file path.
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+    /// Output format.
+    #[clap(short = 'O', long, value_enum)]
+    pub output_format: Option<Format>,
+}
+
+/// Name of the animation, without any extensions (e.g., `Peashooter` for `Peashooter.anim.bin`).
+fn base_name(path: &Path) -> &str {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    name.split('.').next().unwrap_or(name)
+}
+
+/// Guess the part name for a track, if its name suggests a separate part.
+fn part_name(track: &str) -> Option<&str> {
+    let part = track.strip_prefix(META_PREFIX)?;
+    let kind = part.trim_end_matches(|c: char| c.is_ascii_digit());
+    PART_NAMES.contains(&kind).then_some(part)
+}
+
+/// Make a starter model for the animation: one state per meta, and attachments guessed from the
+/// track names. Models for the attachments are assumed to be named `<name>-<part>.model.bin`.
+/// Fails if there is no meta for the default state.
+pub fn scaffold_model(anim: &AnimDesc, animation: PathBuf, name: &str) -> anyhow::Result<Model> {
+    let states = anim.meta.iter()
+        .filter(|meta| part_name(&meta.name).is_none())
+        .map(|meta| State {
+            name: meta.name.strip_prefix(META_PREFIX).unwrap_or(&meta.name).to_string(),
+            frame_rate: None,
+            cool_down: Duration::ZERO,
+            state_meta: Cached::from(meta.name.clone()),
+            transitions: Default::default(),
+        })
+        .collect::<Vec<_>>();
+    let default_state = states.iter()
+        .find(|state| state.state_meta.raw_key == IDLE_META)
+        .or_else(|| states.first())
+        .context("no meta available for the default state")?
+        .name.clone();
+    let mut seen = HashSet::new();
+    let attachments = anim.tracks.iter().map(|track| &track.name)
+        .filter(|track| seen.insert(track.as_str()))
+        .filter_map(|track| Some(Attachment {
+            target_track: track.clone(),
+            child_model: Cached::from(PathBuf::from(format!("{name}-{}.model.bin", part_name(track)?))),
+        }))
+        .collect::<Vec<_>>();
+    Ok(Model {
+        animation: Cached::from(animation),
+        markers: Box::default(),
+        states: states.into(),
+        default_state: Cached::from(default_state),
+        attachments: attachments.into(),
+        hidden_tracks: Box::default(),
+    })
+}
+
+impl ScaffoldArgs {
+    /// Generate the starter model.
+    pub fn run(self) -> anyhow::Result<()> {
+        let pack_input = self.pack_input || Format::infer_packed(&self.input);
+        let input_format = Format::decide(self.input_format, Some(&self.input), Format::Compiled);
+        let anim = decode_anim(&self.input, input_format, pack_input)?;
+        let anim = anim.into_packed(true, &ReanimNameHeuristic)?;
+        let MaybePacked::Packed(anim) = anim else { unreachable!("animation should be packed") };
+
+        let name = base_name(&self.input);
+        let animation = self.animation.unwrap_or_else(|| PathBuf::from(format!("{name}.anim.bin")));
+        let model = scaffold_model(&anim, animation, name)
+            .with_context(|| format!("failed to scaffold a model for {:?}", self.input))?;
+
+        let output_format = Format::decide(self.output_format, self.output.as_ref(), Format::Yaml);
+        if let Some(output) = self.output {
+            let context = || format!("failed to open output file {output:?}");
+            let output = File::create(&output).with_context(context)?;
+            encode_model(model, output_format, output)?;
+        } else {
+            encode_model(model, output_format, std::io::stdout().lock())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! JSON Schema for the structural (JSON/YAML) formats.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use libre_pvz_resources::animation::AnimDesc;
+use libre_pvz_resources::font::FontDesc;
+use libre_pvz_resources::model::{Attachment, Model, PlantMeta, State, StateTransition};
+
+/// Arguments for generating JSON Schema.
+#[derive(Debug, Args)]
+pub struct SchemaArgs {
+    /// Kind of files to describe.
+    #[clap(value_enum)]
+    pub kind: SchemaKind,
+    /// Output file path (defaults to the standard output).
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+/// Kinds of structures with a JSON Schema.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum SchemaKind {
+    /// Models (`.model.json`, `.model.yaml`).
+    Model,
+    /// A single state in a model.
+    State,
+    /// A single state transition in a model.
+    Transition,
+    /// A single attachment in a model.
+    Attachment,
+    /// Plant meta information.
+    PlantMeta,
+    /// Structural animations (`.anim.json`, `.anim.yaml`).
+    Anim,
+    /// Bitmap fonts (`.font.json`, `.font.yaml`).
+    Font,
+}
+
+impl SchemaKind {
+    /// JSON Schema for this kind of structures.
+    pub fn schema(self) -> RootSchema {
+        match self {
+            SchemaKind::Model => schema_for!(Model),
+            SchemaKind::State => schema_for!(State),
+            SchemaKind::Transition => schema_for!(StateTransition),
+            SchemaKind::Attachment => schema_for!(Attachment),
+            SchemaKind::PlantMeta => schema_for!(PlantMeta),
+            SchemaKind::Anim => schema_for!(AnimDesc),
+            SchemaKind::Font => schema_for!(FontDesc),
+        }
+    }
+}
+
+impl SchemaArgs {
+    /// Print the JSON Schema.
+    pub fn run(self) -> anyhow::Result<()> {
+        let schema = self.kind.schema();
+        if let Some(output) = self.output {
+            let context = || format!("failed to open output file {output:?}");
+            let mut output = File::create(&output).with_context(context)?;
+            serde_json::to_writer_pretty(&mut output, &schema)?;
+            writeln!(output)?;
+        } else {
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &schema)?;
+            writeln!(stdout)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,705 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Bitmap font definitions (`data/*.txt` font description scripts) in the original game.
+//!
+//! A font description is a script of statements, each a command followed by its arguments and
+//! terminated by a semicolon. Arguments are bare words (names and numbers), quoted strings, or
+//! parenthesized lists, and names introduced by `Define` can be used in place of their values:
+//!
+//! ```text
+//! Define CharList ('A', 'B', 'V');
+//! Define Rects ((0, 0, 12, 16), (12, 0, 11, 16), (23, 0, 12, 16));
+//! CreateLayer Main;
+//! LayerSetImage Main 'Font12.png';
+//! LayerSetAscent Main 13;
+//! LayerSetImageMap Main CharList Rects;
+//! LayerSetCharWidths Main CharList (12, 11, 12);
+//! LayerSetKerningPairs Main ('AV') (-2);
+//! ```
+//!
+//! [`parse_script`] parses the statements, and [`FontDefinition::from_statements`] runs them to
+//! build a structured font description.
+
+use std::collections::HashMap;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use libre_pvz_resources::cached::Cached;
+use libre_pvz_resources::font as packed;
+
+pub use packed::{Glyph, KerningPair};
+
+/// A bitmap font, built from a font description script.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct FontDefinition {
+    /// Point size of this font, used for choosing layers (`SetDefaultPointSize`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_point_size: Option<i32>,
+    /// Characters mapped to others before looking up the glyphs (`SetCharMap`), e.g., lowercase
+    /// letters for fonts with only uppercase ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub char_map: Vec<(char, char)>,
+    /// Layers, drawn on top of each other, e.g., a shadow and then the glyphs.
+    pub layers: Vec<FontLayer>,
+}
+
+/// A layer in a bitmap font, with its own image and glyph metrics.
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct FontLayer {
+    /// Name of this layer, e.g., `Main`.
+    pub name: String,
+    /// Image containing the glyphs, as a path relative to the font description script.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// This layer is only used if all these tags are requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_tags: Vec<String>,
+    /// This layer is not used if any of these tags is requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_tags: Vec<String>,
+    /// This layer is only used for point sizes in this (inclusive) range.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub point_range: Option<[i32; 2]>,
+    /// Point size of the glyphs in the image.
+    pub point_size: i32,
+    /// Distance from the top of a line to the baseline.
+    pub ascent: i32,
+    /// Extra space above the ascent.
+    pub ascent_padding: i32,
+    /// Height of a line.
+    pub height: i32,
+    /// Adjustment to the distance between consecutive lines.
+    pub line_spacing_offset: i32,
+    /// Extra space between consecutive characters.
+    pub spacing: i32,
+    /// Offset of the whole layer.
+    pub offset: [i32; 2],
+    /// Colour multiplier (RGBA, with components in `[0, 1]`).
+    pub color_mult: [f32; 4],
+    /// Colour added after multiplication (RGBA, with components in `[0, 1]`).
+    pub color_add: [f32; 4],
+    /// Whether or not this layer is drawn with additive blending.
+    pub additive: bool,
+    /// Layers with smaller base orders are drawn first.
+    pub base_order: i32,
+    /// Glyphs in this layer, sorted by their characters.
+    pub glyphs: Vec<Glyph>,
+    /// Kerning pairs in this layer, sorted by the pairs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kerning: Vec<KerningPair>,
+}
+
+impl FontLayer {
+    fn new(name: String) -> FontLayer {
+        FontLayer {
+            name,
+            image: None,
+            required_tags: Vec::new(),
+            excluded_tags: Vec::new(),
+            point_range: None,
+            point_size: 0,
+            ascent: 0,
+            ascent_padding: 0,
+            height: 0,
+            line_spacing_offset: 0,
+            spacing: 0,
+            offset: [0, 0],
+            color_mult: [1.0; 4],
+            color_add: [0.0; 4],
+            additive: false,
+            base_order: 0,
+            glyphs: Vec::new(),
+            kerning: Vec::new(),
+        }
+    }
+
+    /// Get the glyph for some character.
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        let k = self.glyphs.binary_search_by_key(&c, |glyph| glyph.char).ok()?;
+        Some(&self.glyphs[k])
+    }
+
+    fn glyph_mut(&mut self, c: char) -> &mut Glyph {
+        let k = self.glyphs.binary_search_by_key(&c, |glyph| glyph.char).unwrap_or_else(|k| {
+            self.glyphs.insert(k, Glyph { char: c, rect: [0; 4], offset: [0; 2], width: 0 });
+            k
+        });
+        &mut self.glyphs[k]
+    }
+
+    /// Get the kerning offset between two consecutive characters.
+    pub fn kerning(&self, pair: [char; 2]) -> i32 {
+        self.kerning.binary_search_by_key(&pair, |kerning| kerning.pair)
+            .map_or(0, |k| self.kerning[k].offset)
+    }
+}
+
+/// Errors when parsing or running a font description script, with location information.
+#[derive(Debug, Error)]
+#[error("{line}:{column}: {kind}")]
+pub struct FontError {
+    /// Line number (1-based) at which the error is detected.
+    pub line: usize,
+    /// Column number (1-based, in characters) at which the error is detected.
+    pub column: usize,
+    /// What went wrong.
+    pub kind: FontErrorKind,
+}
+
+/// Kinds of errors when parsing or running a font description script.
+#[derive(Debug, Error)]
+pub enum FontErrorKind {
+    /// Unexpected end of input.
+    #[error("unexpected end of input, expecting {0}")]
+    UnexpectedEof(&'static str),
+    /// Unexpected character.
+    #[error("unexpected character '{found}', expecting {expected}")]
+    UnexpectedChar {
+        /// The character we found in the input.
+        found: char,
+        /// Description of what we expect here.
+        expected: &'static str,
+    },
+    /// Wrong number of arguments for a command.
+    #[error("command '{command}' expects {expected} argument(s), found {found}")]
+    ArgumentCount {
+        /// The command.
+        command: String,
+        /// Number of expected arguments.
+        expected: usize,
+        /// Number of arguments we found in the input.
+        found: usize,
+    },
+    /// Invalid argument for a command.
+    #[error("invalid argument #{index} for command '{command}', expecting {expected}")]
+    InvalidArgument {
+        /// The command.
+        command: String,
+        /// Index (1-based) of the argument.
+        index: usize,
+        /// Description of what we expect here.
+        expected: &'static str,
+    },
+    /// Name not introduced by `Define`.
+    #[error("undefined name '{0}'")]
+    Undefined(String),
+    /// Layer not created by `CreateLayer` or `CreateLayerFrom`.
+    #[error("unknown layer '{0}'")]
+    UnknownLayer(String),
+    /// Layer created more than once.
+    #[error("duplicate layer '{0}'")]
+    DuplicateLayer(String),
+    /// Lists of characters and their values differ in length.
+    #[error("command '{command}' got {chars} character(s) but {values} value(s)")]
+    LengthMismatch {
+        /// The command.
+        command: String,
+        /// Length of the character list.
+        chars: usize,
+        /// Length of the value list.
+        values: usize,
+    },
+}
+
+use FontErrorKind::*;
+
+/// A statement in a font description script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    /// Line number (1-based) of this statement.
+    pub line: usize,
+    /// Column number (1-based, in characters) of this statement.
+    pub column: usize,
+    /// The command, e.g., `LayerSetImageMap`.
+    pub command: String,
+    /// Arguments for the command.
+    pub args: Vec<Value>,
+}
+
+/// An argument in a font description script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Bare words: names or numbers.
+    Bare(String),
+    /// Quoted strings, with escapes resolved.
+    Str(String),
+    /// Parenthesized lists.
+    List(Vec<Value>),
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str { &self.src[self.pos..] }
+
+    /// Line and column numbers at the current position.
+    fn location(&self) -> (usize, usize) {
+        let prefix = &self.src[..self.pos];
+        let line = prefix.matches('\n').count() + 1;
+        let line_start = prefix.rfind('\n').map_or(0, |k| k + 1);
+        let column = prefix[line_start..].chars().count() + 1;
+        (line, column)
+    }
+
+    fn error(&self, kind: FontErrorKind) -> FontError {
+        let (line, column) = self.location();
+        FontError { line, column, kind }
+    }
+
+    fn unexpected(&self, expected: &'static str) -> FontError {
+        match self.rest().chars().next() {
+            None => self.error(UnexpectedEof(expected)),
+            Some(found) => self.error(UnexpectedChar { found, expected }),
+        }
+    }
+
+    /// Skip whitespaces and comments (`//` or `#` until the end of line).
+    fn skip_trivia(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !(trimmed.starts_with("//") || trimmed.starts_with('#')) { return; }
+            self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    fn bare(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c: char| c.is_whitespace() || "(),;'\"".contains(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn string(&mut self, quote: char) -> Result<String, FontError> {
+        self.pos += quote.len_utf8();
+        let mut result = String::new();
+        let mut chars = self.rest().chars();
+        loop {
+            let c = match chars.next() {
+                None => {
+                    self.pos = self.src.len();
+                    return Err(self.error(UnexpectedEof("end of string")));
+                }
+                Some(c) if c == quote => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some(c) => c,
+                    None => continue,
+                },
+                Some(c) => c,
+            };
+            result.push(c);
+        }
+        self.pos = self.src.len() - chars.as_str().len();
+        Ok(result)
+    }
+
+    fn list(&mut self) -> Result<Vec<Value>, FontError> {
+        self.pos += 1;
+        let mut values = Vec::new();
+        loop {
+            self.skip_trivia();
+            if self.rest().starts_with(')') {
+                self.pos += 1;
+                return Ok(values);
+            }
+            values.push(self.value("a value or ')'")?);
+            self.skip_trivia();
+            // commas are optional between list elements
+            if self.rest().starts_with(',') { self.pos += 1; }
+        }
+    }
+
+    fn value(&mut self, expected: &'static str) -> Result<Value, FontError> {
+        match self.rest().chars().next() {
+            Some('(') => Ok(Value::List(self.list()?)),
+            Some(quote @ ('\'' | '"')) => Ok(Value::Str(self.string(quote)?)),
+            _ => match self.bare() {
+                "" => Err(self.unexpected(expected)),
+                word => Ok(Value::Bare(word.to_string())),
+            },
+        }
+    }
+
+    fn statement(&mut self) -> Result<Option<Statement>, FontError> {
+        self.skip_trivia();
+        if self.rest().is_empty() { return Ok(None); }
+        let (line, column) = self.location();
+        let command = match self.bare() {
+            "" => return Err(self.unexpected("a command")),
+            command => command.to_string(),
+        };
+        let mut args = Vec::new();
+        loop {
+            self.skip_trivia();
+            if self.rest().starts_with(';') {
+                self.pos += 1;
+                return Ok(Some(Statement { line, column, command, args }));
+            }
+            args.push(self.value("an argument or ';'")?);
+        }
+    }
+}
+
+/// Parse a font description script into statements.
+pub fn parse_script(src: &str) -> Result<Vec<Statement>, FontError> {
+    // skip the UTF-8 byte order mark, if any
+    let pos = if src.starts_with('\u{FEFF}') { '\u{FEFF}'.len_utf8() } else { 0 };
+    let mut parser = Parser { src, pos };
+    std::iter::from_fn(|| parser.statement().transpose()).collect()
+}
+
+/// A statement being executed, for accessing arguments and reporting errors.
+struct Command<'s> {
+    statement: &'s Statement,
+    defines: &'s HashMap<String, Value>,
+}
+
+impl<'s> Command<'s> {
+    fn invalid(&self, index: usize, expected: &'static str) -> FontErrorKind {
+        InvalidArgument { command: self.statement.command.clone(), index: index + 1, expected }
+    }
+
+    fn expect_args(&self, expected: usize) -> Result<(), FontErrorKind> {
+        let found = self.statement.args.len();
+        if found == expected { return Ok(()); }
+        Err(ArgumentCount { command: self.statement.command.clone(), expected, found })
+    }
+
+    /// Get an argument, with defined names substituted.
+    fn arg(&self, index: usize) -> &'s Value {
+        resolve(self.defines, &self.statement.args[index])
+    }
+
+    fn name(&self, index: usize) -> Result<&'s str, FontErrorKind> {
+        match &self.statement.args[index] {
+            Value::Bare(name) | Value::Str(name) => Ok(name),
+            Value::List(_) => Err(self.invalid(index, "a name")),
+        }
+    }
+
+    fn string(&self, index: usize) -> Result<&'s str, FontErrorKind> {
+        match self.arg(index) {
+            Value::Bare(s) | Value::Str(s) => Ok(s),
+            Value::List(_) => Err(self.invalid(index, "a string")),
+        }
+    }
+
+    fn int(&self, index: usize) -> Result<i32, FontErrorKind> {
+        as_int(self.arg(index)).ok_or_else(|| self.invalid(index, "an integer"))
+    }
+
+    fn list(&self, index: usize) -> Result<Vec<&'s Value>, FontErrorKind> {
+        match self.arg(index) {
+            Value::List(values) => Ok(values.iter().map(|value| resolve(self.defines, value)).collect()),
+            Value::Bare(name) if as_int(&self.statement.args[index]).is_none() => Err(Undefined(name.clone())),
+            _ => Err(self.invalid(index, "a list")),
+        }
+    }
+
+    fn map_list<T>(
+        &self, index: usize, expected: &'static str,
+        f: impl Fn(&Value) -> Option<T>,
+    ) -> Result<Vec<T>, FontErrorKind> {
+        self.list(index)?.into_iter()
+            .map(|value| f(value).ok_or_else(|| self.invalid(index, expected)))
+            .collect()
+    }
+
+    fn ints<const N: usize>(&self, index: usize, expected: &'static str) -> Result<[i32; N], FontErrorKind> {
+        let values = self.map_list(index, expected, as_int)?;
+        values.try_into().map_err(|_| self.invalid(index, expected))
+    }
+
+    fn color(&self, index: usize) -> Result<[f32; 4], FontErrorKind> {
+        const EXPECTED: &str = "a list of 3 or 4 colour components";
+        let values = self.map_list(index, EXPECTED, as_float)?;
+        let mut color = match *values.as_slice() {
+            [r, g, b] => [r, g, b, 1.0],
+            [r, g, b, a] => [r, g, b, a],
+            _ => return Err(self.invalid(index, EXPECTED)),
+        };
+        // components are usually in 0..=255, but normalized ones are also accepted
+        if color.iter().any(|&x| x > 1.0) {
+            color.iter_mut().for_each(|x| *x /= 255.0);
+        }
+        Ok(color)
+    }
+
+    /// Zip a list of characters with a list of values.
+    fn char_map<T>(
+        &self, expected: &'static str,
+        f: impl Fn(&Value) -> Option<T>,
+    ) -> Result<Vec<(char, T)>, FontErrorKind> {
+        let chars = self.map_list(1, "a list of characters", as_char)?;
+        let values = self.map_list(2, expected, f)?;
+        if chars.len() != values.len() {
+            return Err(LengthMismatch {
+                command: self.statement.command.clone(),
+                chars: chars.len(),
+                values: values.len(),
+            });
+        }
+        Ok(std::iter::zip(chars, values).collect())
+    }
+}
+
+fn resolve<'s>(defines: &'s HashMap<String, Value>, value: &'s Value) -> &'s Value {
+    match value {
+        Value::Bare(name) => defines.get(name).unwrap_or(value),
+        _ => value,
+    }
+}
+
+fn as_int(value: &Value) -> Option<i32> {
+    match value {
+        Value::Bare(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_float(value: &Value) -> Option<f32> {
+    match value {
+        Value::Bare(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_char(value: &Value) -> Option<char> {
+    let (Value::Bare(s) | Value::Str(s)) = value else { return None };
+    let mut chars = s.chars();
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
+
+fn as_ints<const N: usize>(value: &Value) -> Option<[i32; N]> {
+    let Value::List(values) = value else { return None };
+    values.iter().map(as_int).collect::<Option<Vec<_>>>()?.try_into().ok()
+}
+
+fn as_pair(value: &Value) -> Option<[char; 2]> {
+    let (Value::Bare(s) | Value::Str(s)) = value else { return None };
+    s.chars().collect::<Vec<_>>().try_into().ok()
+}
+
+impl FontDefinition {
+    /// Parse and run a font description script.
+    pub fn from_script(src: &str) -> Result<FontDefinition, FontError> {
+        FontDefinition::from_statements(&parse_script(src)?)
+    }
+
+    /// Run the statements of a font description script.
+    pub fn from_statements(statements: &[Statement]) -> Result<FontDefinition, FontError> {
+        let mut font = FontDefinition::default();
+        let mut defines = HashMap::new();
+        for statement in statements {
+            font.run(statement, &mut defines).map_err(|kind| FontError {
+                line: statement.line,
+                column: statement.column,
+                kind,
+            })?;
+        }
+        font.layers.sort_by_key(|layer| layer.base_order);
+        Ok(font)
+    }
+
+    /// Pack this font, with image paths resolved against `dir` (the directory of the font
+    /// description script, relative to the asset directory).
+    ///
+    /// Only the layers used by default are kept, i.e., those with images and without
+    /// [`required_tags`](FontLayer::required_tags). Layers are drawn with normal blending, and
+    /// [`color_add`](FontLayer::color_add) is dropped.
+    pub fn pack_with(self, dir: &Path) -> packed::FontDesc {
+        let layers = self.layers.into_iter().filter_map(|layer| {
+            if !layer.required_tags.is_empty() {
+                tracing::debug!(target: "pack", "dropped layer '{}' requiring tags {:?}", layer.name, layer.required_tags);
+                return None;
+            }
+            let Some(image) = layer.image else {
+                tracing::warn!(target: "pack", "dropped layer '{}' without an image", layer.name);
+                return None;
+            };
+            if layer.additive || layer.color_add.iter().any(|&x| x != 0.0) {
+                tracing::warn!(target: "pack", "dropped additive blending and colour in layer '{}'", layer.name);
+            }
+            // use the tallest glyph for the line height if not specified
+            let height = if layer.height > 0 { layer.height } else {
+                layer.glyphs.iter().map(|glyph| glyph.rect[3]).max().unwrap_or(0)
+            };
+            Some(packed::FontLayer {
+                image: Cached::from(dir.join(image)),
+                ascent: layer.ascent,
+                line_spacing: height + layer.line_spacing_offset,
+                spacing: layer.spacing,
+                offset: layer.offset,
+                color: layer.color_mult,
+                glyphs: layer.glyphs.into(),
+                kerning: layer.kerning.into(),
+            })
+        }).collect();
+        packed::FontDesc { char_map: self.char_map, layers }
+    }
+
+    /// Get the layer with the given name.
+    pub fn layer(&self, name: &str) -> Option<&FontLayer> {
+        self.layers.iter().find(|layer| layer.name == name)
+    }
+
+    fn layer_mut(&mut self, cmd: &Command) -> Result<&mut FontLayer, FontErrorKind> {
+        let name = cmd.name(0)?;
+        self.layers.iter_mut().find(|layer| layer.name == name)
+            .ok_or_else(|| UnknownLayer(name.to_string()))
+    }
+
+    fn create_layer(&mut self, layer: FontLayer) -> Result<(), FontErrorKind> {
+        if self.layer(&layer.name).is_some() { return Err(DuplicateLayer(layer.name)); }
+        self.layers.push(layer);
+        Ok(())
+    }
+
+    fn run(&mut self, statement: &Statement, defines: &mut HashMap<String, Value>) -> Result<(), FontErrorKind> {
+        let cmd = Command { statement, defines };
+        match statement.command.as_str() {
+            "Define" => {
+                cmd.expect_args(2)?;
+                let name = cmd.name(0)?.to_string();
+                let value = cmd.arg(1).clone();
+                defines.insert(name, value);
+            }
+            "CreateLayer" => {
+                cmd.expect_args(1)?;
+                self.create_layer(FontLayer::new(cmd.name(0)?.to_string()))?;
+            }
+            "CreateLayerFrom" => {
+                cmd.expect_args(2)?;
+                let source = cmd.name(1)?;
+                let source = self.layer(source).ok_or_else(|| UnknownLayer(source.to_string()))?;
+                let layer = FontLayer { name: cmd.name(0)?.to_string(), ..source.clone() };
+                self.create_layer(layer)?;
+            }
+            "SetDefaultPointSize" => {
+                cmd.expect_args(1)?;
+                self.default_point_size = Some(cmd.int(0)?);
+            }
+            "SetCharMap" => {
+                cmd.expect_args(2)?;
+                let from = cmd.map_list(0, "a list of characters", as_char)?;
+                let to = cmd.map_list(1, "a list of characters", as_char)?;
+                if from.len() != to.len() {
+                    let command = statement.command.clone();
+                    return Err(LengthMismatch { command, chars: from.len(), values: to.len() });
+                }
+                self.char_map.extend(std::iter::zip(from, to));
+            }
+            "LayerSetImage" => {
+                cmd.expect_args(2)?;
+                self.layer_mut(&cmd)?.image = Some(cmd.string(1)?.to_string());
+            }
+            "LayerRequireTags" | "LayerExcludeTags" => {
+                cmd.expect_args(2)?;
+                let tags = cmd.map_list(1, "a list of tags", |value| match value {
+                    Value::Bare(s) | Value::Str(s) => Some(s.to_uppercase()),
+                    Value::List(_) => None,
+                })?;
+                let layer = self.layer_mut(&cmd)?;
+                match statement.command.as_str() {
+                    "LayerRequireTags" => layer.required_tags.extend(tags),
+                    _ => layer.excluded_tags.extend(tags),
+                }
+            }
+            "LayerPointRange" => {
+                cmd.expect_args(3)?;
+                let range = [cmd.int(1)?, cmd.int(2)?];
+                self.layer_mut(&cmd)?.point_range = Some(range);
+            }
+            "LayerSetPointSize" | "LayerSetHeight" | "LayerSetAscent" | "LayerSetAscentPadding"
+            | "LayerSetLineSpacingOffset" | "LayerSetSpacing" | "LayerSetBaseOrder" | "LayerSetDrawMode" => {
+                cmd.expect_args(2)?;
+                let value = cmd.int(1)?;
+                let layer = self.layer_mut(&cmd)?;
+                match statement.command.as_str() {
+                    "LayerSetPointSize" => layer.point_size = value,
+                    "LayerSetHeight" => layer.height = value,
+                    "LayerSetAscent" => layer.ascent = value,
+                    "LayerSetAscentPadding" => layer.ascent_padding = value,
+                    "LayerSetLineSpacingOffset" => layer.line_spacing_offset = value,
+                    "LayerSetSpacing" => layer.spacing = value,
+                    "LayerSetBaseOrder" => layer.base_order = value,
+                    // 0 for normal, 1 for additive
+                    _ => layer.additive = value == 1,
+                }
+            }
+            "LayerSetOffset" => {
+                cmd.expect_args(2)?;
+                let offset = cmd.ints(1, "a list of 2 integers")?;
+                self.layer_mut(&cmd)?.offset = offset;
+            }
+            "LayerSetColorMult" | "LayerSetColorAdd" => {
+                cmd.expect_args(2)?;
+                let color = cmd.color(1)?;
+                let layer = self.layer_mut(&cmd)?;
+                match statement.command.as_str() {
+                    "LayerSetColorMult" => layer.color_mult = color,
+                    _ => layer.color_add = color,
+                }
+            }
+            "LayerSetImageMap" => {
+                cmd.expect_args(3)?;
+                let rects = cmd.char_map("a list of rectangles (x, y, width, height)", as_ints::<4>)?;
+                let layer = self.layer_mut(&cmd)?;
+                for (c, rect) in rects { layer.glyph_mut(c).rect = rect; }
+            }
+            "LayerSetCharOffsets" => {
+                cmd.expect_args(3)?;
+                let offsets = cmd.char_map("a list of offsets (x, y)", as_ints::<2>)?;
+                let layer = self.layer_mut(&cmd)?;
+                for (c, offset) in offsets { layer.glyph_mut(c).offset = offset; }
+            }
+            "LayerSetCharWidths" => {
+                cmd.expect_args(3)?;
+                let widths = cmd.char_map("a list of integers", as_int)?;
+                let layer = self.layer_mut(&cmd)?;
+                for (c, width) in widths { layer.glyph_mut(c).width = width; }
+            }
+            "LayerSetKerningPairs" => {
+                cmd.expect_args(3)?;
+                let pairs = cmd.map_list(1, "a list of character pairs", as_pair)?;
+                let offsets = cmd.map_list(2, "a list of integers", as_int)?;
+                if pairs.len() != offsets.len() {
+                    let command = statement.command.clone();
+                    return Err(LengthMismatch { command, chars: pairs.len(), values: offsets.len() });
+                }
+                let layer = self.layer_mut(&cmd)?;
+                for (pair, offset) in std::iter::zip(pairs, offsets) {
+                    match layer.kerning.binary_search_by_key(&pair, |kerning| kerning.pair) {
+                        Ok(k) => layer.kerning[k].offset = offset,
+                        Err(k) => layer.kerning.insert(k, KerningPair { pair, offset }),
+                    }
+                }
+            }
+            command => tracing::warn!("ignored unknown command '{command}' in font description"),
+        }
+        Ok(())
+    }
+}
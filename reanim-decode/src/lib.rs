@@ -21,9 +21,19 @@
 #![warn(missing_docs)]
 #![warn(missing_debug_implementations)]
 
+// for the derive macros to refer to this crate as `reanim_decode` uniformly
+extern crate self as reanim_decode;
+
 #[macro_use]
 pub mod stream;
 pub mod reanim;
+pub mod particles;
+pub mod trail;
+pub mod font;
+pub mod optimize;
+#[cfg(feature = "render")]
+pub mod render;
+pub mod resources;
 pub mod xml;
 
 #[cfg(feature = "cli")]
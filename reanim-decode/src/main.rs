@@ -20,6 +20,7 @@ use reanim_decode::cli::Cli;
 
 fn main() {
     if let Err(err) = Cli::run() {
-        tracing::error!("{err}");
+        tracing::error!("{err:#}");
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,177 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Keyframe optimization for packed animations.
+//!
+//! Keyframes are sampled with linear interpolation for [`Alpha`](Action::Alpha),
+//! [`Translation`](Action::Translation), [`Scale`](Action::Scale), and
+//! [`Rotation`](Action::Rotation), and as step functions for [`Show`](Action::Show) and
+//! [`LoadElement`](Action::LoadElement). A keyframe is dropped if the sampled values do not change
+//! (within a tolerance) without it.
+
+use std::fmt::{Display, Formatter};
+use std::mem::Discriminant;
+use libre_pvz_resources::animation::{Action, AnimDesc, Track};
+
+/// Statistics of an optimization pass.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct OptimizeStats {
+    /// Number of actions before optimization.
+    pub before: usize,
+    /// Number of actions after optimization.
+    pub after: usize,
+}
+
+impl OptimizeStats {
+    /// Number of dropped actions.
+    pub fn dropped(&self) -> usize { self.before - self.after }
+}
+
+impl Display for OptimizeStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ratio = if self.before == 0 { 0.0 } else { self.dropped() as f64 / self.before as f64 };
+        write!(f, "{} -> {} actions ({} dropped, {:.1}%)", self.before, self.after, self.dropped(), ratio * 100.0)
+    }
+}
+
+/// Numeric components of an interpolated action, or [`None`] for step actions.
+fn components(action: &Action) -> Option<[f32; 2]> {
+    match *action {
+        Action::Alpha(a) => Some([a, a]),
+        Action::Translation(v) | Action::Scale(v) | Action::Rotation(v) => Some(v),
+        Action::LoadElement(_) | Action::Show(_) => None,
+    }
+}
+
+/// Do two actions (of the same kind) sample to the same value?
+fn step_eq(x: &Action, y: &Action) -> bool {
+    match (x, y) {
+        (Action::LoadElement(x), Action::LoadElement(y)) => x == y,
+        (Action::Show(x), Action::Show(y)) => x == y,
+        _ => false,
+    }
+}
+
+/// Is `v` (at frame `k`) within tolerance of the line from `a` (at frame `ka`) to `b` (at `kb`)?
+fn on_line((ka, a): (usize, [f32; 2]), (kb, b): (usize, [f32; 2]), (k, v): (usize, [f32; 2]), tolerance: f32) -> bool {
+    let t = if kb == ka { 0.0 } else { (k - ka) as f32 / (kb - ka) as f32 };
+    (0..2).all(|i| (a[i] + (b[i] - a[i]) * t - v[i]).abs() <= tolerance)
+}
+
+/// Decide which keyframes (of the same kind) to keep.
+fn keep_keyframes(keys: &[(usize, &Action)], protected: &[bool], tolerance: f32) -> Vec<bool> {
+    let mut keep = vec![true; keys.len()];
+    let mut anchor = 0;
+    // keyframes dropped since the anchor, with their numeric components
+    let mut pending = Vec::new();
+    for i in 1..keys.len() {
+        if protected[i] {
+            anchor = i;
+            pending.clear();
+            continue;
+        }
+        let (k, action) = keys[i];
+        let droppable = match (components(keys[anchor].1), components(action)) {
+            (Some(a), Some(v)) => {
+                let anchor = (keys[anchor].0, a);
+                // without a next keyframe, the value of the anchor holds afterwards
+                let next = keys.get(i + 1)
+                    .and_then(|&(kn, next)| Some((kn, components(next)?)))
+                    .unwrap_or((k, a));
+                pending.iter().chain([&(k, v)])
+                    .all(|&point| on_line(anchor, next, point, tolerance))
+            }
+            _ => step_eq(keys[anchor].1, action),
+        };
+        if droppable {
+            keep[i] = false;
+            if let Some(v) = components(action) { pending.push((k, v)); }
+        } else {
+            anchor = i;
+            pending.clear();
+        }
+    }
+    keep
+}
+
+/// Drop redundant and linearly interpolable keyframes in a track.
+///
+/// For each kind of actions, the first and the last keyframes are always kept, as well as the last
+/// keyframe at or before each of the `boundaries` (frame indices, e.g., start and end frames of
+/// metas), so that looping within a segment and the frame count are not affected.
+pub fn optimize_track(track: &mut Track, boundaries: &[usize], tolerance: f32) -> OptimizeStats {
+    let mut stats = OptimizeStats::default();
+    let mut kinds = Vec::<Discriminant<Action>>::new();
+    for frame in track.frames.iter() {
+        stats.before += frame.0.len();
+        for action in frame.0.iter() {
+            let kind = std::mem::discriminant(action);
+            if !kinds.contains(&kind) { kinds.push(kind); }
+        }
+    }
+
+    let mut dropped = Vec::new();
+    for kind in kinds {
+        // (frame index, index in frame), and the action itself
+        let (indices, keys): (Vec<_>, Vec<_>) = track.frames.iter().enumerate()
+            .flat_map(|(k, frame)| frame.0.iter().enumerate()
+                .filter(|(_, action)| std::mem::discriminant(*action) == kind)
+                .map(move |(j, action)| ((k, j), (k, action))))
+            .unzip();
+        let mut protected = vec![false; keys.len()];
+        protected[0] = true;
+        protected[keys.len() - 1] = true;
+        for &boundary in boundaries {
+            // the segment starts (or loops back) from the last keyframe before the boundary
+            let last = keys.partition_point(|&(k, _)| k <= boundary);
+            if let Some(last) = last.checked_sub(1) { protected[last] = true; }
+        }
+        let keep = keep_keyframes(&keys, &protected, tolerance);
+        dropped.extend(std::iter::zip(indices, keep).filter(|(_, keep)| !keep).map(|(index, _)| index));
+    }
+
+    dropped.sort_unstable();
+    for (k, frame) in track.frames.iter_mut().enumerate() {
+        let start = dropped.partition_point(|&(kd, _)| kd < k);
+        let end = dropped.partition_point(|&(kd, _)| kd <= k);
+        if start == end { continue; }
+        let actions = std::mem::take(&mut frame.0).into_vec();
+        frame.0 = actions.into_iter().enumerate()
+            .filter(|(j, _)| !dropped[start..end].contains(&(k, *j)))
+            .map(|(_, action)| action)
+            .collect();
+    }
+    stats.after = stats.before - dropped.len();
+    stats
+}
+
+/// Drop redundant and linearly interpolable keyframes in all tracks of an animation, keeping
+/// those at the boundaries of metas. See [`optimize_track`].
+pub fn optimize_anim(anim: &mut AnimDesc, tolerance: f32) -> OptimizeStats {
+    let boundaries = anim.meta.iter()
+        .flat_map(|meta| [meta.start_frame as usize, meta.end_frame as usize])
+        .collect::<Vec<_>>();
+    let mut stats = OptimizeStats::default();
+    for track in anim.tracks.iter_mut() {
+        let track_stats = optimize_track(track, &boundaries, tolerance);
+        tracing::debug!(target: "optimize", "track '{}': {track_stats}", track.name);
+        stats.before += track_stats.before;
+        stats.after += track_stats.after;
+    }
+    stats
+}
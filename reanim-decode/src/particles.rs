@@ -0,0 +1,439 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Definition and decoding logic for compiled particle definitions (`.xml.compiled` files).
+//!
+//! Compiled definitions are memory dumps of the definition structures (32bit), with pointers
+//! zeroed out. The data pointed to follows the structure, in the order of the fields: strings
+//! are prefixed by their lengths; non-empty arrays are prefixed by the element size; float tracks
+//! are bare arrays of nodes, whose counts are stored in the structure.
+
+use std::io::BufRead;
+use serde::{Serialize, Deserialize};
+use libre_pvz_resources::param::{Curve, FloatTrack, TrackNode};
+use crate::stream::{decompress_and_decode, Context, Decode, Magic, PlainData, Stream, Result};
+
+macro_rules! plain_enum {
+    (
+        $(#[$attr:meta])* $vis:vis enum $name:ident: $c_name:literal {
+            $($(#[$field_attr:meta])* $field:ident = $value:tt),+ $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+        #[derive(bincode::Encode, bincode::Decode)]
+        #[serde(rename_all = "snake_case")]
+        $vis enum $name { $($(#[$field_attr])* $field = $value),+ }
+
+        impl PlainData for $name {
+            const SIZE_IN_BYTES: usize = 4;
+            const TYPE_NAME: &'static str = $c_name;
+            fn from_bytes(data: &[u8]) -> Option<Self> {
+                match u32::from_bytes(data)? {
+                    $($value => Some($name::$field),)+
+                    _ => None,
+                }
+            }
+            fn to_bytes(&self, data: &mut [u8]) { (*self as u32).to_bytes(data) }
+        }
+    }
+}
+
+plain_enum! {
+    /// Shape of the area where the particles are spawned.
+    pub enum EmitterType: "EmitterType" {
+        /// Inside a circle of radius `emitter_radius`.
+        Circle = 0,
+        /// Inside a box of size `emitter_box_x` by `emitter_box_y`.
+        Box = 1,
+        /// Along the path of a box.
+        BoxPath = 2,
+        /// Along the path of a circle.
+        CirclePath = 3,
+        /// Along the path of a circle, evenly spaced.
+        CircleEvenSpacing = 4,
+    }
+}
+
+plain_enum! {
+    /// Kind of a [`ParticleField`].
+    pub enum FieldType: "ParticleFieldType" {
+        #[allow(missing_docs)] Invalid = 0,
+        #[allow(missing_docs)] Friction = 1,
+        #[allow(missing_docs)] Acceleration = 2,
+        #[allow(missing_docs)] Attractor = 3,
+        #[allow(missing_docs)] MaxVelocity = 4,
+        #[allow(missing_docs)] Velocity = 5,
+        #[allow(missing_docs)] Position = 6,
+        #[allow(missing_docs)] SystemPosition = 7,
+        #[allow(missing_docs)] GroundConstraint = 8,
+        #[allow(missing_docs)] Shake = 9,
+        #[allow(missing_docs)] Circle = 10,
+        #[allow(missing_docs)] Away = 11,
+    }
+}
+
+/// Flags for an [`Emitter`], stored as a bit set in the compiled definitions.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(bincode::Encode, bincode::Decode)]
+#[serde(rename_all = "snake_case")]
+#[allow(missing_docs)]
+pub enum ParticleFlag {
+    RandomLaunchSpin,
+    AlignLaunchSpin,
+    AlignToPixels,
+    SystemLoops,
+    ParticleLoops,
+    ParticlesDontFollow,
+    RandomStartTime,
+    DieIfOverloaded,
+    Additive,
+    Fullscreen,
+    SoftwareOnly,
+    HardwareOnly,
+}
+
+impl ParticleFlag {
+    /// All the flags, in the order of their bit positions.
+    pub const ALL: [ParticleFlag; 12] = {
+        use ParticleFlag::*;
+        [
+            RandomLaunchSpin, AlignLaunchSpin, AlignToPixels, SystemLoops, ParticleLoops,
+            ParticlesDontFollow, RandomStartTime, DieIfOverloaded, Additive, Fullscreen,
+            SoftwareOnly, HardwareOnly,
+        ]
+    };
+
+    fn from_bits(bits: u32) -> Vec<ParticleFlag> {
+        let unknown = bits >> ParticleFlag::ALL.len();
+        if unknown != 0 {
+            tracing::warn!("ignored unknown particle flags: {:#X}", unknown << ParticleFlag::ALL.len());
+        }
+        ParticleFlag::ALL.into_iter()
+            .enumerate()
+            .filter(|(k, _)| bits & (1 << k) != 0)
+            .map(|(_, flag)| flag)
+            .collect()
+    }
+}
+
+impl PlainData for Curve {
+    const SIZE_IN_BYTES: usize = 4;
+    const TYPE_NAME: &'static str = "TodCurves";
+    fn from_bytes(data: &[u8]) -> Option<Self> { Curve::try_from(u32::from_bytes(data)?).ok() }
+    fn to_bytes(&self, data: &mut [u8]) { (*self as u32).to_bytes(data) }
+}
+
+declare_no_args!(TrackNode);
+impl Decode<()> for TrackNode {
+    fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<TrackNode> {
+        let time = s.read_field::<f32>("time")?;
+        let low = s.read_field::<f32>("low")?;
+        let high = s.read_field::<f32>("high")?;
+        let curve = s.read_field::<Curve>("curve")?;
+        let distribution = s.read_field::<Curve>("distribution")?;
+        Ok(TrackNode { time, low, high, curve, distribution })
+    }
+}
+
+declare_no_args!(FloatTrack);
+impl Decode<usize> for FloatTrack {
+    fn decode_with<S: Stream + ?Sized>(s: &mut S, n: usize) -> Result<FloatTrack> {
+        Ok(FloatTrack(s.read_n_indexed("node", n)?.into_boxed_slice()))
+    }
+}
+
+/// Read the pointer (dropped) and node count of a [`FloatTrack`].
+pub(crate) fn read_track_count<S: Stream + ?Sized>(s: &mut S) -> Result<usize> {
+    s.drop_padding("track", 4)?;
+    Ok(s.read_data::<u32>()? as usize)
+}
+
+/// Check the element size before a non-empty array.
+fn check_element_size<S: Stream + ?Sized>(s: &mut S, count: usize, size: u32) -> Result<()> {
+    if count == 0 { Ok(()) } else { s.check_magic(size) }
+}
+
+macro_rules! float_tracks {
+    ($(#[$attr:meta])* $name:ident { $($(#[$field_attr:meta])* $field:ident),+ $(,)? }) => {
+        $(#[$attr])*
+        #[derive(Debug, Default, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+        pub struct $name {
+            $(
+                $(#[$field_attr])*
+                #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+                pub $field: FloatTrack,
+            )+
+        }
+
+        impl $name {
+            fn read_counts<S: Stream + ?Sized>(s: &mut S) -> Result<Vec<usize>> {
+                [$(stringify!($field)),+].into_iter()
+                    .map(|field| s.with_context(Context::Field(field), read_track_count))
+                    .collect()
+            }
+
+            fn read_tracks<S: Stream + ?Sized>(s: &mut S, counts: &[usize]) -> Result<$name> {
+                let mut counts = counts.iter().copied();
+                Ok($name {
+                    $($field: s.with_context(Context::Field(stringify!($field)), |s| {
+                        FloatTrack::decode_with(s, counts.next().unwrap_or_default())
+                    })?,)+
+                })
+            }
+        }
+    }
+}
+
+float_tracks! {
+    /// Float tracks for the emitter as a whole, over the lifetime of the particle system.
+    SystemTracks {
+        /// Lifetime of the particle system, in centiseconds.
+        system_duration,
+        /// Duration for cross-fading into another particle system.
+        cross_fade_duration,
+        /// Particles spawned per second.
+        spawn_rate,
+        /// Minimum number of active particles.
+        spawn_min_active,
+        /// Maximum number of active particles.
+        spawn_max_active,
+        /// Maximum number of particles launched in total.
+        spawn_max_launched,
+        #[allow(missing_docs)] emitter_radius,
+        #[allow(missing_docs)] emitter_offset_x,
+        #[allow(missing_docs)] emitter_offset_y,
+        #[allow(missing_docs)] emitter_box_x,
+        #[allow(missing_docs)] emitter_box_y,
+        #[allow(missing_docs)] emitter_skew_x,
+        #[allow(missing_docs)] emitter_skew_y,
+        #[allow(missing_docs)] emitter_path,
+        /// Lifetime of each particle, in centiseconds.
+        particle_duration,
+        #[allow(missing_docs)] launch_speed,
+        #[allow(missing_docs)] launch_angle,
+        #[allow(missing_docs)] system_red,
+        #[allow(missing_docs)] system_green,
+        #[allow(missing_docs)] system_blue,
+        #[allow(missing_docs)] system_alpha,
+        #[allow(missing_docs)] system_brightness,
+    }
+}
+
+float_tracks! {
+    /// Float tracks for each particle, over the lifetime of the particle.
+    ParticleTracks {
+        #[allow(missing_docs)] particle_red,
+        #[allow(missing_docs)] particle_green,
+        #[allow(missing_docs)] particle_blue,
+        #[allow(missing_docs)] particle_alpha,
+        #[allow(missing_docs)] particle_brightness,
+        #[allow(missing_docs)] particle_spin_angle,
+        #[allow(missing_docs)] particle_spin_speed,
+        #[allow(missing_docs)] particle_scale,
+        #[allow(missing_docs)] particle_stretch,
+        #[allow(missing_docs)] collision_reflect,
+        #[allow(missing_docs)] collision_spin,
+        #[allow(missing_docs)] clip_top,
+        #[allow(missing_docs)] clip_bottom,
+        #[allow(missing_docs)] clip_left,
+        #[allow(missing_docs)] clip_right,
+        /// Frame rate for animated particle images.
+        animation_rate,
+    }
+}
+
+/// A field affecting the motion of particles, e.g., acceleration and friction.
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct ParticleField {
+    /// Kind of this field.
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// Parameter along the x-axis.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub x: FloatTrack,
+    /// Parameter along the y-axis.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub y: FloatTrack,
+}
+
+/// Size of a `ParticleField` in the compiled definitions.
+const PARTICLE_FIELD_SIZE: u32 = 0x14;
+
+fn read_fields<S: Stream + ?Sized>(s: &mut S, count: usize) -> Result<Box<[ParticleField]>> {
+    check_element_size(s, count, PARTICLE_FIELD_SIZE)?;
+    let headers = (0..count).map(|k| s.with_context(Context::Index("field", k), |s| {
+        let field_type = s.read_field::<FieldType>("type")?;
+        let x_count = read_track_count(s)?;
+        let y_count = read_track_count(s)?;
+        Ok((field_type, x_count, y_count))
+    })).collect::<Result<Vec<_>>>()?;
+    headers.into_iter().enumerate().map(|(k, (field_type, x_count, y_count))| {
+        s.with_context(Context::Index("field", k), |s| {
+            let x = s.with_context(Context::Field("x"), |s| FloatTrack::decode_with(s, x_count))?;
+            let y = s.with_context(Context::Field("y"), |s| FloatTrack::decode_with(s, y_count))?;
+            Ok(ParticleField { field_type, x, y })
+        })
+    }).collect()
+}
+
+/// A particle emitter, as in `<Emitter>` in the particle definition XML files.
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Emitter {
+    /// Name of this emitter.
+    pub name: String,
+    /// Image for the particles, e.g., `IMAGE_PEA_SPLATS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// Number of columns in the image, if it is an atlas.
+    pub image_col: u32,
+    /// Number of rows in the image, if it is an atlas.
+    pub image_row: u32,
+    /// Number of frames for animated particles.
+    pub image_frames: u32,
+    /// Whether or not the particle images are animated.
+    pub animated: u32,
+    /// Flags for this emitter.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<ParticleFlag>,
+    /// Shape of the spawning area.
+    pub emitter_type: EmitterType,
+    /// Name of the emitter to switch to when this one finishes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_duration: Option<String>,
+    /// Float tracks for the whole particle system.
+    #[serde(flatten)]
+    pub system_tracks: SystemTracks,
+    /// Fields affecting the particles.
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    pub particle_fields: Box<[ParticleField]>,
+    /// Fields affecting the particle system as a whole.
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    pub system_fields: Box<[ParticleField]>,
+    /// Float tracks for each particle.
+    #[serde(flatten)]
+    pub particle_tracks: ParticleTracks,
+}
+
+/// Size of a `TodEmitterDefinition` in the compiled definitions.
+const EMITTER_SIZE: u32 = 0x164;
+
+/// Fixed-size part of an [`Emitter`] in the compiled definitions.
+struct EmitterHeader {
+    image_col: u32,
+    image_row: u32,
+    image_frames: u32,
+    animated: u32,
+    flags: u32,
+    emitter_type: EmitterType,
+    system_counts: Vec<usize>,
+    particle_field_count: usize,
+    system_field_count: usize,
+    particle_counts: Vec<usize>,
+}
+
+declare_no_args!(EmitterHeader);
+impl Decode<()> for EmitterHeader {
+    fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<EmitterHeader> {
+        s.drop_padding("image", 4)?;
+        let image_col = s.read_data::<u32>()?;
+        let image_row = s.read_data::<u32>()?;
+        let image_frames = s.read_data::<u32>()?;
+        let animated = s.read_data::<u32>()?;
+        let flags = s.read_data::<u32>()?;
+        let emitter_type = s.read_data::<EmitterType>()?;
+        s.drop_padding("name", 4)?;
+        s.drop_padding("on-duration", 4)?;
+        let system_counts = SystemTracks::read_counts(s)?;
+        s.drop_padding("fields", 4)?;
+        let particle_field_count = s.read_data::<u32>()? as usize;
+        s.drop_padding("system-fields", 4)?;
+        let system_field_count = s.read_data::<u32>()? as usize;
+        let particle_counts = ParticleTracks::read_counts(s)?;
+        Ok(EmitterHeader {
+            image_col, image_row, image_frames, animated, flags, emitter_type,
+            system_counts, particle_field_count, system_field_count, particle_counts,
+        })
+    }
+}
+declare_no_args!(Emitter);
+impl Decode<EmitterHeader> for Emitter {
+    fn decode_with<S: Stream + ?Sized>(s: &mut S, header: EmitterHeader) -> Result<Emitter> {
+        fn opt(s: String) -> Option<String> {
+            if s.is_empty() { None } else { Some(s) }
+        }
+        let image = opt(s.read_string_field("image")?);
+        let name = s.read_string_field("name")?;
+        tracing::debug!("decoding Emitter '{name}' (XML tag <Emitter>)");
+        s.with_context(Context::Named("emitter", name.clone()), |s| {
+            let on_duration = opt(s.read_string_field("on_duration")?);
+            let system_tracks = SystemTracks::read_tracks(s, &header.system_counts)?;
+            let particle_fields = s.with_context(Context::Field("particle_fields"), |s| {
+                read_fields(s, header.particle_field_count)
+            })?;
+            let system_fields = s.with_context(Context::Field("system_fields"), |s| {
+                read_fields(s, header.system_field_count)
+            })?;
+            let particle_tracks = ParticleTracks::read_tracks(s, &header.particle_counts)?;
+            Ok(Emitter {
+                name, image,
+                image_col: header.image_col,
+                image_row: header.image_row,
+                image_frames: header.image_frames,
+                animated: header.animated,
+                flags: ParticleFlag::from_bits(header.flags),
+                emitter_type: header.emitter_type,
+                on_duration,
+                system_tracks, particle_fields, system_fields, particle_tracks,
+            })
+        })
+    }
+}
+
+/// Particle definition in a `.xml` file under `particles`.
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct ParticleDefinition {
+    /// All emitters in this particle system.
+    pub emitters: Box<[Emitter]>,
+}
+
+impl ParticleDefinition {
+    /// Decode a `.xml.compiled` particle definition.
+    /// Performs decompression before decoding if necessary.
+    pub fn decompress_and_decode<R: BufRead + ?Sized>(s: &mut R) -> Result<ParticleDefinition> {
+        decompress_and_decode(s)
+    }
+}
+
+declare_no_args!(ParticleDefinition);
+impl Decode<()> for ParticleDefinition {
+    fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<ParticleDefinition> {
+        tracing::debug!("decoding ParticleDefinition (XML root node)");
+        // hash of the definition schema, not checked here
+        let schema = s.read_data::<Magic>()?;
+        tracing::debug!("definition schema hash: {schema}");
+        s.drop_padding("emitters", 4)?;
+        let count = s.read_data::<u32>()? as usize;
+        check_element_size(s, count, EMITTER_SIZE)?;
+        let headers = s.read_n_indexed::<EmitterHeader>("emitter", count)?;
+        let emitters = headers.into_iter()
+            .map(|header| Emitter::decode_with(s, header))
+            .collect::<Result<Box<[_]>>>()?;
+        Ok(ParticleDefinition { emitters })
+    }
+}
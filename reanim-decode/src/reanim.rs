@@ -18,15 +18,17 @@
 
 //! Definition and decoding logic for `.reanim.compiled` files.
 
-use std::io::BufRead;
-use std::path::PathBuf;
-use flate2::bufread::ZlibDecoder;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
 use serde::{Serialize, Deserialize};
 use libre_pvz_resources::animation as packed;
-use libre_pvz_resources::animation::Element;
+use libre_pvz_resources::animation::{Element, META_RANGE_SEPARATOR};
 use libre_pvz_resources::cached::{Cached, SortedSlice};
 use packed::Action;
-use crate::stream::{Decode, DecodeExt, Stream, Result};
+use crate::stream::{decompress_and_decode, Context, Decode, Encode, Sink, Stream, Result};
 
 /// Animation in a `.reanim` file.
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,13 +42,22 @@ pub struct Animation {
 impl Animation {
     /// Decode a `.reanim` or `.reanim.compiled` file.
     /// Performs decompression before decoding if necessary.
-    pub fn decompress_and_decode<R: Stream + BufRead + ?Sized>(s: &mut R) -> Result<Animation> {
-        if let Ok([0xD4, 0xFE, 0xAD, 0xDE, ..]) = s.fill_buf() {
-            s.consume(8);
-            Animation::decode(&mut ZlibDecoder::new(s))
-        } else {
-            Animation::decode(s)
-        }
+    pub fn decompress_and_decode<R: BufRead + ?Sized>(s: &mut R) -> Result<Animation> {
+        decompress_and_decode(s)
+    }
+
+    /// Encode as a `.reanim.compiled` file, optionally compressed (as in the original game).
+    /// Padding is always written as zeros, see [`Stream::drop_padding`].
+    pub fn encode_and_compress<W: Write + ?Sized>(&self, w: &mut W, compress: bool) -> std::io::Result<()> {
+        if !compress { return self.encode(w); }
+        let mut buffer = Vec::new();
+        self.encode(&mut buffer)?;
+        w.write_magic(0xDE_AD_FE_D4)?;
+        w.write_data(&(buffer.len() as u32))?;
+        let mut encoder = ZlibEncoder::new(w, Compression::default());
+        encoder.write_all(&buffer)?;
+        encoder.finish()?;
+        Ok(())
     }
 }
 
@@ -57,14 +68,14 @@ impl Decode<()> for Animation {
         s.check_magic(0xB3_93_B4_C0)?;
         s.drop_padding("after-magic", 4)?;
         let track_count = s.read_data::<u32>()? as usize;
-        let fps = s.read_data::<f32>()?;
+        let fps = s.read_field::<f32>("fps")?;
         s.drop_padding("prop", 4)?;
         s.check_magic(0x0C)?;
         let mut tracks = Vec::with_capacity(track_count);
-        let frame_counts = std::iter::repeat_with(|| {
+        let frame_counts = (0..track_count).map(|k| s.with_context(Context::Index("track", k), |s| {
             s.drop_padding("frame", 8)?;
-            s.read_data::<u32>()
-        }).take(track_count).collect::<Result<Vec<_>>>()?;
+            s.read_field::<u32>("frame_count")
+        })).collect::<Result<Vec<_>>>()?;
         for frame_count in frame_counts {
             tracks.push(Track::decode_with(s, frame_count as usize)?);
         }
@@ -72,6 +83,23 @@ impl Decode<()> for Animation {
     }
 }
 
+impl Encode for Animation {
+    fn encode<S: Sink + ?Sized>(&self, s: &mut S) -> std::io::Result<()> {
+        tracing::debug!("encoding Animation (XML root node)");
+        s.write_magic(0xB3_93_B4_C0)?;
+        s.write_padding("after-magic", 4)?;
+        s.write_data(&(self.tracks.len() as u32))?;
+        s.write_data(&self.fps)?;
+        s.write_padding("prop", 4)?;
+        s.write_magic(0x0C)?;
+        for track in self.tracks.iter() {
+            s.write_padding("frame", 8)?;
+            s.write_data(&(track.frames.len() as u32))?;
+        }
+        s.write_n(&self.tracks)
+    }
+}
+
 macro_rules! narrow {
     ($n:expr, $on_err:expr, $or_else:expr) => {
         match $n.try_into() {
@@ -84,7 +112,7 @@ macro_rules! narrow {
     }
 }
 
-fn track_to_meta(track: packed::Track) -> Result<packed::Meta, packed::Track> {
+fn track_to_meta(track: packed::Track) -> Result<Vec<packed::Meta>, packed::Track> {
     let mut ranges = Vec::new();
     let mut ignored_count = 0_usize;
     // visible by default from the start
@@ -120,65 +148,175 @@ fn track_to_meta(track: packed::Track) -> Result<packed::Meta, packed::Track> {
     if current_visible {
         ranges.push((last_key_frame, track.frames.len()));
     }
-    // only one range is allowed
-    if let [(start_frame, end_frame)] = ranges[..] {
-        let on_err = |n: usize| tracing::error!(target: "pack", "frame index ({n}) overflow in a meta track");
+    if ranges.is_empty() {
+        tracing::warn!(target: "pack", "meta track {} is never visible", track.name);
+        return Err(track);
+    }
+    let on_err = |n: usize| tracing::error!(target: "pack", "frame index ({n}) overflow in a meta track");
+    let mut metas = Vec::with_capacity(ranges.len());
+    for (k, &(start_frame, end_frame)) in ranges.iter().enumerate() {
         let start_frame = narrow!(start_frame, on_err, track);
         let end_frame = narrow!(end_frame - 1, on_err, track);
-        if ignored_count > 0 {
-            tracing::warn!(target: "pack", "ignored {ignored_count} transform/alpha in meta track {}", track.name);
-        }
-        Ok(packed::Meta { name: track.name, start_frame, end_frame })
-    } else {
-        tracing::warn!(target: "pack", "discontinuous meta track {}: found ranges {ranges:?}", track.name);
-        Err(track)
+        // discontinuous meta tracks are split into several metas, one for each range
+        let name = match ranges.len() {
+            1 => track.name.clone(),
+            _ => format!("{}{META_RANGE_SEPARATOR}{k}", track.name),
+        };
+        metas.push(packed::Meta { name, start_frame, end_frame });
+    }
+    if ignored_count > 0 {
+        tracing::warn!(target: "pack", "ignored {ignored_count} transform/alpha in meta track {}", track.name);
+    }
+    if ranges.len() > 1 {
+        tracing::info!(target: "pack", "split discontinuous meta track {}: found ranges {ranges:?}", track.name);
+    }
+    Ok(metas)
+}
+
+/// Resolve image identifiers (e.g., `IMAGE_REANIM_PEASHOOTER_HEAD`) to image file paths, and font
+/// identifiers (e.g., `FONT_BRIANNETOD16`) to packed font assets (`*.font.bin`).
+pub trait ImageResolver {
+    /// Get the file path for an image identifier, or [`None`] if it cannot be resolved.
+    fn resolve(&self, id: &str) -> Option<PathBuf>;
+    /// Get the image identifier for a file path, the inverse of [`resolve`](Self::resolve).
+    fn image_id(&self, path: &Path) -> Option<String>;
+    /// Get the asset path for a font identifier, or [`None`] if it cannot be resolved.
+    fn resolve_font(&self, _id: &str) -> Option<PathBuf> { None }
+    /// Get the font identifier for an asset path, the inverse of [`resolve_font`](Self::resolve_font).
+    fn font_id(&self, _path: &Path) -> Option<String> { None }
+}
+
+/// Guess image file names from `IMAGE_REANIM_*` identifiers: strip the prefix, convert to lower
+/// case (except for the first letter), and append `.png`. Font file names are guessed likewise
+/// from `FONT_*` identifiers, in the `data` directory, with the extension `.font.bin`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ReanimNameHeuristic;
+
+impl ReanimNameHeuristic {
+    fn guess(id: &str, prefix: &str, ext: &str) -> Option<String> {
+        let mut name = id.strip_prefix(prefix)?.to_string();
+        name.get_mut(1..)?.make_ascii_lowercase();
+        name.push_str(ext);
+        Some(name)
     }
 }
 
+impl ImageResolver for ReanimNameHeuristic {
+    fn resolve(&self, id: &str) -> Option<PathBuf> {
+        ReanimNameHeuristic::guess(id, "IMAGE_REANIM_", ".png").map(PathBuf::from)
+    }
+    fn image_id(&self, path: &Path) -> Option<String> { image_id_from_path(path) }
+    fn resolve_font(&self, id: &str) -> Option<PathBuf> {
+        ReanimNameHeuristic::guess(id, "FONT_", ".font.bin").map(|font| Path::new("data").join(font))
+    }
+    fn font_id(&self, path: &Path) -> Option<String> { font_id_from_path(path) }
+}
+
 impl From<Animation> for packed::AnimDesc {
-    fn from(anim: Animation) -> packed::AnimDesc {
+    fn from(anim: Animation) -> packed::AnimDesc { anim.pack_with(&ReanimNameHeuristic) }
+}
+
+impl Animation {
+    /// Pack this animation, resolving image names using the given [`ImageResolver`].
+    pub fn pack_with<R: ImageResolver + ?Sized>(self, resolver: &R) -> packed::AnimDesc {
         let mut metas = Vec::new();
         let mut tracks = Vec::new();
-        for track in anim.tracks.into_vec().into_iter().map(packed::Track::from) {
+        for track in self.tracks.into_vec().into_iter().map(|track| track.pack_with(resolver)) {
             match track_to_meta(track) {
-                Ok(meta) => metas.push(meta),
+                Ok(meta) => metas.extend(meta),
                 Err(track) => tracks.push(track),
             }
         }
         packed::AnimDesc {
-            fps: anim.fps,
+            fps: self.fps,
             meta: SortedSlice::from(metas),
             tracks: tracks.into_boxed_slice(),
         }
     }
 }
 
+impl From<packed::AnimDesc> for Animation {
+    fn from(anim: packed::AnimDesc) -> Animation { Animation::unpack_with(anim, &ReanimNameHeuristic) }
+}
+
+impl Animation {
+    /// Unpack an animation, mapping image paths back to names using the given [`ImageResolver`].
+    pub fn unpack_with<R: ImageResolver + ?Sized>(anim: packed::AnimDesc, resolver: &R) -> Animation {
+        let frame_count = anim.tracks.iter()
+            .map(|track| track.frames.len())
+            .chain(anim.meta.iter().map(|meta| meta.end_frame as usize + 1))
+            .max().unwrap_or(0);
+        // metas split from a discontinuous meta track are merged back
+        let mut grouped = BTreeMap::<&str, Vec<&packed::Meta>>::new();
+        for meta in anim.meta.iter() {
+            grouped.entry(meta.split_name().0).or_default().push(meta);
+        }
+        let metas = grouped.into_iter().map(|(name, ranges)| meta_to_track(name, ranges, frame_count));
+        let tracks = anim.tracks.into_vec().into_iter().map(|track| Track::unpack_with(track, resolver));
+        Animation { fps: anim.fps, tracks: metas.chain(tracks).collect() }
+    }
+}
+
+fn meta_to_track(name: &str, mut ranges: Vec<&packed::Meta>, frame_count: usize) -> Track {
+    let mut frames = std::iter::repeat_with(Frame::default).take(frame_count).collect::<Box<[_]>>();
+    ranges.sort_by_key(|meta| meta.start_frame);
+    // visible by default from the start
+    if ranges.first().is_some_and(|meta| meta.start_frame > 0) {
+        frames[0].transform.f = Some(-1.0);
+    }
+    for meta in ranges {
+        let start_frame = meta.start_frame as usize;
+        let end_frame = meta.end_frame as usize;
+        if start_frame > 0 {
+            frames[start_frame].transform.f = Some(0.0);
+        }
+        if end_frame + 1 < frame_count {
+            frames[end_frame + 1].transform.f = Some(-1.0);
+        }
+    }
+    Track { name: name.to_string(), frames }
+}
+
 /// A single track in an [`Animation`].
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Decode)]
+#[decode(args = usize)]
 pub struct Track {
     /// Name of this track for internal use.
+    #[decode(context = "track")]
     pub name: String,
     /// Frames, possibly grouped into several parts.
+    #[decode(magic = 0x2C, with = decode_frames, args = args, no_context)]
     pub frames: Box<[Frame]>,
 }
 
-declare_no_args!(Track);
-impl Decode<usize> for Track {
-    fn decode_with<S: Stream + ?Sized>(s: &mut S, n: usize) -> Result<Self> {
-        let name = s.read_string()?;
-        tracing::debug!("decoding Track '{name}' of length {n} (XML tag <track>)");
-        s.check_magic(0x2C)?;
-        let transforms = s.read_n::<Transform>(n)?;
-        let elements = s.read_n::<Elements>(n)?;
-        let frames = std::iter::zip(transforms, elements)
-            .map(|(transform, elements)| Frame { transform, elements })
-            .collect();
-        Ok(Track { name, frames })
+/// Frames in a track are stored as all the transforms followed by all the elements.
+fn decode_frames<S: Stream + ?Sized>(s: &mut S, n: usize) -> Result<Box<[Frame]>> {
+    tracing::debug!("decoding {n} frames (XML tag <t>)");
+    let transforms = s.read_n_indexed::<Transform>("frame", n)?;
+    let elements = s.read_n_indexed::<Elements>("frame", n)?;
+    Ok(std::iter::zip(transforms, elements)
+        .map(|(transform, elements)| Frame { transform, elements })
+        .collect())
+}
+
+impl Encode for Track {
+    fn encode<S: Sink + ?Sized>(&self, s: &mut S) -> std::io::Result<()> {
+        tracing::debug!("encoding Track '{}' of length {} (XML tag <track>)", self.name, self.frames.len());
+        s.write_string(&self.name)?;
+        s.write_magic(0x2C)?;
+        self.frames.iter().try_for_each(|frame| frame.transform.encode(s))?;
+        self.frames.iter().try_for_each(|frame| frame.elements.encode(s))
     }
 }
 
 impl From<Track> for packed::Track {
-    fn from(track: Track) -> packed::Track {
+    fn from(track: Track) -> packed::Track { track.pack_with(&ReanimNameHeuristic) }
+}
+
+impl Track {
+    /// Pack this track, resolving image names using the given [`ImageResolver`].
+    pub fn pack_with<R: ImageResolver + ?Sized>(self, resolver: &R) -> packed::Track {
+        let track = self;
         let mut frames = Vec::with_capacity(track.frames.len());
         #[derive(Copy, Clone)]
         struct RawTrans {
@@ -226,29 +364,22 @@ impl From<Track> for packed::Track {
             // elements: text OR image
             let Elements { text, font, image } = frame.elements;
             let mut has_image = false;
-            if let Some(mut image) = image {
-                let mut image_name_valid = false;
-                if let Some(s) = image.strip_prefix("IMAGE_REANIM_") {
-                    image = s.to_string();
-                    if let Some(tail) = image.get_mut(1..) {
-                        tail.make_ascii_lowercase();
-                        image.push_str(".png");
-                        image_name_valid = true;
-                    }
-                }
-                if !image_name_valid {
+            if let Some(image) = image {
+                let image = resolver.resolve(&image).unwrap_or_else(|| {
                     tracing::error!(target: "pack", "exotic file name: {image}");
-                }
-                let image = Cached::from(PathBuf::from(image));
+                    PathBuf::from(image)
+                });
+                let image = Cached::from(image);
                 packed.push(Action::LoadElement(Element::Image { image }));
                 has_image = true;
             }
             match (text, font) {
                 (Some(text), Some(font)) => if has_image {
                     tracing::warn!(target: "pack", "dropped <text>{text}</text> in favour of <i>");
+                } else if let Some(font) = resolver.resolve_font(&font) {
+                    packed.push(Action::LoadElement(Element::Text { text, font: Cached::from(font) }));
                 } else {
-                    let font = Cached::from(PathBuf::from(font));
-                    packed.push(Action::LoadElement(Element::Text { text, font }));
+                    tracing::warn!(target: "pack", "dropped <text>{text}</text> with unknown font: {font}");
                 },
                 (Some(text), None) => tracing::warn!(target: "pack", "dropped <text>{text}</text> without <font>"),
                 (None, Some(font)) => tracing::warn!(target: "pack", "dropped <font>{font}</font> without <text>"),
@@ -260,8 +391,75 @@ impl From<Track> for packed::Track {
     }
 }
 
+impl From<packed::Track> for Track {
+    fn from(track: packed::Track) -> Track { Track::unpack_with(track, &ReanimNameHeuristic) }
+}
+
+impl Track {
+    /// Unpack a track, mapping image paths back to names using the given [`ImageResolver`].
+    pub fn unpack_with<R: ImageResolver + ?Sized>(track: packed::Track, resolver: &R) -> Track {
+        let frames = track.frames.into_vec().into_iter().map(|frame| {
+            let mut transform = Transform::default();
+            let mut elements = Elements::default();
+            for action in frame.0.into_vec() {
+                match action {
+                    // subtracting from zero avoids negative zeros in the output
+                    Action::Translation([x, y]) => {
+                        transform.x = Some(x);
+                        transform.y = Some(0.0 - y);
+                    }
+                    Action::Scale([sx, sy]) => {
+                        transform.sx = Some(sx);
+                        transform.sy = Some(sy);
+                    }
+                    // see the comments in packing for why 'kx' is negated
+                    Action::Rotation([kx, ky]) => {
+                        transform.kx = Some((0.0 - kx).to_degrees());
+                        transform.ky = Some(ky.to_degrees());
+                    }
+                    Action::Alpha(a) => transform.a = Some(a),
+                    Action::Show(visible) => transform.f = Some(if visible { 0.0 } else { -1.0 }),
+                    Action::LoadElement(Element::Image { image }) => {
+                        let path = &image.raw_key;
+                        elements.image = Some(resolver.image_id(path).unwrap_or_else(|| {
+                            tracing::error!(target: "unpack", "exotic file name: {}", path.display());
+                            path.to_string_lossy().into_owned()
+                        }));
+                    }
+                    Action::LoadElement(Element::Text { text, font }) => {
+                        elements.text = Some(text);
+                        let path = &font.raw_key;
+                        elements.font = Some(resolver.font_id(path).unwrap_or_else(|| {
+                            tracing::error!(target: "unpack", "exotic font name: {}", path.display());
+                            path.to_string_lossy().into_owned()
+                        }));
+                    }
+                }
+            }
+            Frame { transform, elements }
+        }).collect();
+        Track { name: track.name, frames }
+    }
+}
+
+/// Map an image path back to its `IMAGE_REANIM_*` identifier, the inverse of
+/// [`ReanimNameHeuristic`]. Paths not produced by the heuristic are rejected.
+pub fn image_id_from_path(path: &Path) -> Option<String> {
+    let stem = path.to_str()?.strip_suffix(".png")?;
+    let id = format!("IMAGE_REANIM_{}", stem.to_ascii_uppercase());
+    (ReanimNameHeuristic.resolve(&id)? == path).then_some(id)
+}
+
+/// Map a font path back to its `FONT_*` identifier, the inverse of [`ReanimNameHeuristic`].
+/// Paths not produced by the heuristic are rejected.
+pub fn font_id_from_path(path: &Path) -> Option<String> {
+    let stem = path.strip_prefix("data").ok()?.to_str()?.strip_suffix(".font.bin")?;
+    let id = format!("FONT_{}", stem.to_ascii_uppercase());
+    (ReanimNameHeuristic.resolve_font(&id)? == path).then_some(id)
+}
+
 /// A transformation.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, Decode)]
 #[allow(missing_docs)]
 pub struct Transform {
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -279,53 +477,46 @@ pub struct Transform {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub f: Option<f32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[decode(padding_after = 12)]
     pub a: Option<f32>,
 }
 
-declare_no_args!(Transform);
-impl Decode<()> for Transform {
-    fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<Transform> {
-        tracing::debug!("decoding Transform (XML tag <t>)");
-        let x = s.read_optional::<f32>()?;
-        let y = s.read_optional::<f32>()?;
-        let kx = s.read_optional::<f32>()?;
-        let ky = s.read_optional::<f32>()?;
-        let sx = s.read_optional::<f32>()?;
-        let sy = s.read_optional::<f32>()?;
-        let f = s.read_optional::<f32>()?;
-        let a = s.read_optional::<f32>()?;
-        s.drop_padding("transform", 12)?;
-        Ok(Transform { x, y, kx, ky, sx, sy, f, a })
+impl Encode for Transform {
+    fn encode<S: Sink + ?Sized>(&self, s: &mut S) -> std::io::Result<()> {
+        tracing::debug!("encoding Transform (XML tag <t>)");
+        let Transform { x, y, kx, ky, sx, sy, f, a } = self;
+        for field in [x, y, kx, ky, sx, sy, f, a] {
+            s.write_optional(field)?;
+        }
+        s.write_padding("transform", 12)
     }
 }
 
 /// An element in a [`Frame`].
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, Decode)]
 #[allow(missing_docs)]
 pub struct Elements {
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[decode(optional)]
     pub image: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[decode(optional)]
     pub font: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[decode(optional)]
     pub text: Option<String>,
 }
 
-declare_no_args!(Elements);
-impl Decode<()> for Elements {
-    fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<Elements> {
-        fn opt(s: String) -> Option<String> {
-            if s.is_empty() { None } else { Some(s) }
-        }
-        let image_name = opt(s.read_string()?);
-        let font_name = opt(s.read_string()?);
-        let text = opt(s.read_string()?);
-        Ok(Elements { image: image_name, font: font_name, text })
+impl Encode for Elements {
+    fn encode<S: Sink + ?Sized>(&self, s: &mut S) -> std::io::Result<()> {
+        s.write_string(self.image.as_deref().unwrap_or_default())?;
+        s.write_string(self.font.as_deref().unwrap_or_default())?;
+        s.write_string(self.text.as_deref().unwrap_or_default())
     }
 }
 
 /// A frame in a [`Track`], consist of (optional) image, text, and transformation.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Frame {
     /// Transformation: translation, skew, rotation, etc.
     #[serde(flatten)]
@@ -0,0 +1,376 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Software rendering of packed animations, e.g., for thumbnails and sprite sheets.
+//!
+//! Keyframes are sampled in the same way as the animation player: linear interpolation for alpha
+//! and transforms, and step functions for visibility and images. Transforms use the same affine
+//! math as `From<&Transform2D> for Affine3A` in `libre_pvz_animation`, and images are anchored
+//! at their top-left corners.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use image::{Delay, Frame as ImageFrame, ImageError, RgbaImage};
+use image::codecs::gif::{GifEncoder, Repeat};
+use thiserror::Error;
+use libre_pvz_resources::animation::{Action, AnimDesc, Element, Track};
+
+/// Errors during rendering.
+#[derive(Debug, Error)]
+pub enum RenderError {
+    /// Failed to load an image referenced in the animation.
+    #[error("failed to load image {path:?}: {error}")]
+    LoadImage {
+        /// Path to the image file.
+        path: PathBuf,
+        /// The actual error.
+        #[source]
+        error: ImageError,
+    },
+    /// Failed to encode the output.
+    #[error("failed to encode output: {0}")]
+    Encode(#[from] ImageError),
+    /// The requested meta does not exist.
+    #[error("unknown meta '{0}'")]
+    UnknownMeta(String),
+    /// The requested frame is out of range.
+    #[error("frame {0} out of range (only {1} frames available)")]
+    FrameOutOfRange(usize, usize),
+}
+
+/// Result type for rendering.
+pub type Result<T> = std::result::Result<T, RenderError>;
+
+/// 2D affine transformation, with the matrix stored in columns.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Affine {
+    mat: [[f32; 2]; 2],
+    trans: [f32; 2],
+}
+
+impl Affine {
+    fn new(translation: [f32; 2], scale: [f32; 2], rotation: [f32; 2]) -> Affine {
+        Affine {
+            mat: [
+                [scale[0] * rotation[0].cos(), scale[0] * rotation[0].sin()],
+                [scale[1] * rotation[1].sin(), scale[1] * rotation[1].cos()],
+            ],
+            trans: translation,
+        }
+    }
+
+    fn apply(&self, [x, y]: [f32; 2]) -> [f32; 2] {
+        [
+            self.mat[0][0] * x + self.mat[1][0] * y + self.trans[0],
+            self.mat[0][1] * x + self.mat[1][1] * y + self.trans[1],
+        ]
+    }
+
+    fn inverse(&self) -> Option<Affine> {
+        let [[a, b], [c, d]] = self.mat;
+        let det = a * d - b * c;
+        if det.abs() < f32::EPSILON { return None; }
+        let mat = [[d / det, -b / det], [-c / det, a / det]];
+        let [x, y] = self.trans;
+        let trans = [-(mat[0][0] * x + mat[1][0] * y), -(mat[0][1] * x + mat[1][1] * y)];
+        Some(Affine { mat, trans })
+    }
+}
+
+/// Sampled state of a track at some frame.
+#[derive(Debug, Clone)]
+struct TrackState<'a> {
+    translation: [f32; 2],
+    scale: [f32; 2],
+    rotation: [f32; 2],
+    alpha: f32,
+    visible: bool,
+    image: Option<&'a Path>,
+}
+
+impl Default for TrackState<'_> {
+    fn default() -> Self {
+        TrackState {
+            translation: [0.0, 0.0],
+            scale: [1.0, 1.0],
+            rotation: [0.0, 0.0],
+            alpha: 1.0,
+            visible: true,
+            image: None,
+        }
+    }
+}
+
+fn kind_index(action: &Action) -> usize {
+    match action {
+        Action::LoadElement(_) => 0,
+        Action::Alpha(_) => 1,
+        Action::Show(_) => 2,
+        Action::Translation(_) => 3,
+        Action::Scale(_) => 4,
+        Action::Rotation(_) => 5,
+    }
+}
+
+fn lerp(x: [f32; 2], y: [f32; 2], t: f32) -> [f32; 2] {
+    [x[0] + (y[0] - x[0]) * t, x[1] + (y[1] - x[1]) * t]
+}
+
+impl<'a> TrackState<'a> {
+    /// Sample a track at some frame.
+    fn sample(track: &'a Track, k: usize) -> TrackState<'a> {
+        // for each kind of actions: the last keyframe at or before 'k', and the next one
+        let mut last = [None; 6];
+        let mut next = [None; 6];
+        for (i, frame) in track.frames.iter().enumerate() {
+            for action in frame.0.iter() {
+                let kind = kind_index(action);
+                if i <= k {
+                    last[kind] = Some((i, action));
+                } else if next[kind].is_none() {
+                    next[kind] = Some((i, action));
+                }
+            }
+        }
+        let mut state = TrackState::default();
+        for (last, next) in std::iter::zip(last, next) {
+            let Some((i, action)) = last else { continue };
+            let t = next.map_or(0.0, |(j, _)| (k - i) as f32 / (j - i) as f32);
+            let next = next.map(|(_, next)| next);
+            match (action, next) {
+                (Action::LoadElement(Element::Image { image }), _) => state.image = Some(&image.raw_key),
+                (Action::LoadElement(Element::Text { .. }), _) => state.image = None,
+                (&Action::Show(visible), _) => state.visible = visible,
+                (&Action::Alpha(a), Some(&Action::Alpha(b))) => state.alpha = a + (b - a) * t,
+                (&Action::Alpha(a), _) => state.alpha = a,
+                (&Action::Translation(x), Some(&Action::Translation(y))) => state.translation = lerp(x, y, t),
+                (&Action::Translation(x), _) => state.translation = x,
+                (&Action::Scale(x), Some(&Action::Scale(y))) => state.scale = lerp(x, y, t),
+                (&Action::Scale(x), _) => state.scale = x,
+                (&Action::Rotation(x), Some(&Action::Rotation(y))) => state.rotation = lerp(x, y, t),
+                (&Action::Rotation(x), _) => state.rotation = x,
+            }
+        }
+        state
+    }
+}
+
+/// A sprite to draw on the canvas.
+#[derive(Debug)]
+struct Sprite<'a> {
+    image: &'a RgbaImage,
+    transform: Affine,
+    alpha: f32,
+}
+
+impl Sprite<'_> {
+    /// Corners of this sprite in the animation coordinates (y-axis pointing upwards).
+    fn corners(&self) -> [[f32; 2]; 4] {
+        let (w, h) = (self.image.width() as f32, self.image.height() as f32);
+        // anchored at the top-left corner, and image rows go downwards
+        [[0.0, 0.0], [w, 0.0], [0.0, -h], [w, -h]].map(|p| self.transform.apply(p))
+    }
+}
+
+/// Bounding box of the rendered area, in the animation coordinates (y-axis pointing upwards).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bounds {
+    /// Left edge.
+    pub left: f32,
+    /// Top edge.
+    pub top: f32,
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+}
+
+impl Bounds {
+    fn from_points(points: impl IntoIterator<Item = [f32; 2]>) -> Option<Bounds> {
+        let mut points = points.into_iter().peekable();
+        points.peek()?;
+        let (mut min, mut max) = ([f32::INFINITY; 2], [f32::NEG_INFINITY; 2]);
+        for p in points {
+            for i in 0..2 {
+                min[i] = min[i].min(p[i]);
+                max[i] = max[i].max(p[i]);
+            }
+        }
+        let (left, top) = (min[0].floor(), max[1].ceil());
+        let width = (max[0].ceil() - left).max(1.0) as u32;
+        let height = (top - min[1].floor()).max(1.0) as u32;
+        Some(Bounds { left, top, width, height })
+    }
+}
+
+/// Load an image as RGBA.
+fn load_image(path: &Path) -> Result<RgbaImage> {
+    let image = image::open(path).map_err(|error| RenderError::LoadImage { path: path.to_path_buf(), error })?;
+    Ok(image.into_rgba8())
+}
+
+/// Bilinear sampling, with transparent pixels outside the image. Returns premultiplied colours.
+fn sample_bilinear(image: &RgbaImage, x: f32, y: f32) -> [f32; 4] {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let pixel = |x: f32, y: f32| -> [f32; 4] {
+        if x < 0.0 || y < 0.0 || x >= image.width() as f32 || y >= image.height() as f32 {
+            return [0.0; 4];
+        }
+        let [r, g, b, a] = image.get_pixel(x as u32, y as u32).0.map(|c| c as f32 / 255.0);
+        [r * a, g * a, b * a, a]
+    };
+    let mix = |p: [f32; 4], q: [f32; 4], t: f32| std::array::from_fn(|i| p[i] + (q[i] - p[i]) * t);
+    let top = mix(pixel(x0, y0), pixel(x0 + 1.0, y0), tx);
+    let bottom = mix(pixel(x0, y0 + 1.0), pixel(x0 + 1.0, y0 + 1.0), tx);
+    mix(top, bottom, ty)
+}
+
+/// Software renderer for a packed animation.
+#[derive(Debug)]
+pub struct Renderer<'a> {
+    anim: &'a AnimDesc,
+    images: HashMap<&'a Path, RgbaImage>,
+}
+
+impl<'a> Renderer<'a> {
+    /// Prepare for rendering an animation, loading images relative to the asset directory.
+    pub fn new(anim: &'a AnimDesc, assets: &Path) -> Result<Renderer<'a>> {
+        for track in anim.tracks.iter() {
+            let has_text = track.frames.iter().flat_map(|frame| frame.0.iter())
+                .any(|action| matches!(action, Action::LoadElement(Element::Text { .. })));
+            if has_text {
+                tracing::warn!(target: "render", "text elements in track '{}' are not rendered", track.name);
+            }
+        }
+        let mut images = HashMap::new();
+        for image in anim.image_files() {
+            let path = image.raw_key.as_path();
+            if !images.contains_key(path) {
+                images.insert(path, load_image(&assets.join(path))?);
+            }
+        }
+        Ok(Renderer { anim, images })
+    }
+
+    /// Number of frames in the animation (of the longest track).
+    pub fn frame_count(&self) -> usize {
+        self.anim.tracks.iter().map(|track| track.frames.len()).max().unwrap_or(0)
+    }
+
+    /// Frame range (inclusive) for a meta, or the whole animation.
+    pub fn frame_range(&self, meta: Option<&str>) -> Result<RangeInclusive<usize>> {
+        match meta {
+            None => Ok(0..=self.frame_count().saturating_sub(1)),
+            Some(name) => {
+                let (_, meta) = self.anim.get_meta(name)
+                    .ok_or_else(|| RenderError::UnknownMeta(name.to_string()))?;
+                Ok(meta.start_frame as usize..=meta.end_frame as usize)
+            }
+        }
+    }
+
+    /// Visible sprites at some frame, from the bottom to the top.
+    fn sprites(&self, k: usize) -> Vec<Sprite<'_>> {
+        self.anim.tracks.iter()
+            .map(|track| TrackState::sample(track, k))
+            .filter(|state| state.visible && state.alpha > 0.0)
+            .filter_map(|state| Some(Sprite {
+                image: self.images.get(state.image?)?,
+                transform: Affine::new(state.translation, state.scale, state.rotation),
+                alpha: state.alpha.min(1.0),
+            }))
+            .collect()
+    }
+
+    /// Bounding box covering all the visible sprites in the given frames.
+    pub fn bounds(&self, frames: impl IntoIterator<Item = usize>) -> Option<Bounds> {
+        Bounds::from_points(frames.into_iter()
+            .flat_map(|k| self.sprites(k))
+            .flat_map(|sprite| sprite.corners()))
+    }
+
+    /// Render a single frame onto a canvas of the given bounds.
+    pub fn render_frame(&self, k: usize, bounds: &Bounds) -> Result<RgbaImage> {
+        if k >= self.frame_count() {
+            return Err(RenderError::FrameOutOfRange(k, self.frame_count()));
+        }
+        let (width, height) = (bounds.width as usize, bounds.height as usize);
+        // premultiplied colours
+        let mut canvas = vec![[0.0_f32; 4]; width * height];
+        for sprite in self.sprites(k) {
+            let Some(inverse) = sprite.transform.inverse() else { continue };
+            let Some(area) = Bounds::from_points(sprite.corners()) else { continue };
+            let x0 = (area.left - bounds.left).max(0.0) as usize;
+            let y0 = (bounds.top - area.top).max(0.0) as usize;
+            let x1 = (x0 + area.width as usize + 1).min(width);
+            let y1 = (y0 + area.height as usize + 1).min(height);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    // pixel centre, in the animation coordinates
+                    let p = [bounds.left + x as f32 + 0.5, bounds.top - y as f32 - 0.5];
+                    let [u, v] = inverse.apply(p);
+                    let src = sample_bilinear(sprite.image, u - 0.5, -v - 0.5);
+                    let dst = &mut canvas[y * width + x];
+                    let a = src[3] * sprite.alpha;
+                    for i in 0..4 {
+                        dst[i] = src[i] * sprite.alpha + dst[i] * (1.0 - a);
+                    }
+                }
+            }
+        }
+        Ok(RgbaImage::from_fn(bounds.width, bounds.height, |x, y| {
+            let [r, g, b, a] = canvas[y as usize * width + x as usize];
+            let unmultiply = |c: f32| if a > 0.0 { (c / a * 255.0).round().clamp(0.0, 255.0) as u8 } else { 0 };
+            image::Rgba([unmultiply(r), unmultiply(g), unmultiply(b), (a * 255.0).round().clamp(0.0, 255.0) as u8])
+        }))
+    }
+
+    /// Render a range of frames onto canvases of the same size.
+    pub fn render_frames(&self, frames: RangeInclusive<usize>) -> Result<Vec<RgbaImage>> {
+        let bounds = self.bounds(frames.clone())
+            .unwrap_or(Bounds { left: 0.0, top: 0.0, width: 1, height: 1 });
+        frames.map(|k| self.render_frame(k, &bounds)).collect()
+    }
+}
+
+/// Arrange frames (of the same size) into a sprite sheet, row by row.
+pub fn sprite_sheet(frames: &[RgbaImage], columns: u32) -> RgbaImage {
+    let Some(first) = frames.first() else { return RgbaImage::new(1, 1) };
+    let columns = columns.clamp(1, frames.len() as u32);
+    let rows = (frames.len() as u32).div_ceil(columns);
+    let (width, height) = first.dimensions();
+    let mut sheet = RgbaImage::new(width * columns, height * rows);
+    for (k, frame) in frames.iter().enumerate() {
+        let (x, y) = (k as u32 % columns * width, k as u32 / columns * height);
+        image::imageops::replace(&mut sheet, frame, x as i64, y as i64);
+    }
+    sheet
+}
+
+/// Encode frames as a looping animated GIF.
+pub fn encode_gif<W: Write>(frames: Vec<RgbaImage>, fps: f32, output: W) -> Result<()> {
+    let delay = Delay::from_saturating_duration(Duration::from_secs_f32(1.0 / fps.max(1.0)));
+    let mut encoder = GifEncoder::new(output);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(frames.into_iter().map(|frame| ImageFrame::from_parts(frame, 0, 0, delay)))?;
+    Ok(())
+}
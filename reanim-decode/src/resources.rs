@@ -0,0 +1,236 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Resource manifest (`properties/resources.xml`) in the original game.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::reanim::ImageResolver;
+use crate::xml::{Parser, Tag, XmlError, XmlErrorKind};
+
+/// The resource manifest, resolving resource identifiers to file paths.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ResourceManifest {
+    /// Resource groups, loaded together by the game.
+    pub groups: Vec<ResourceGroup>,
+}
+
+/// A group of resources, as in `<Resources id="...">`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResourceGroup {
+    /// Identifier for this group, e.g., `DelayLoad_Almanac`.
+    pub id: String,
+    /// All the resources in this group.
+    pub resources: Vec<Resource>,
+}
+
+/// Kind of a resource.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceKind {
+    /// `<Image>` resources.
+    Image,
+    /// `<Sound>` resources.
+    Sound,
+    /// `<Font>` resources.
+    Font,
+}
+
+/// A single resource.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resource {
+    /// Kind of this resource.
+    pub kind: ResourceKind,
+    /// Full identifier, with the `idprefix` applied, e.g., `IMAGE_REANIM_PEASHOOTER_HEAD`.
+    pub id: String,
+    /// Path relative to the game directory, with the default path applied, and without an
+    /// extension (the game tries several extensions in turn).
+    pub path: PathBuf,
+    /// Separate image for the alpha channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alpha_image: Option<PathBuf>,
+    /// Separate image for the alpha channel, as a grid used for all the cells.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alpha_grid: Option<PathBuf>,
+    /// Number of rows, if this image is an atlas.
+    #[serde(default = "defaults::one", skip_serializing_if = "defaults::is_one")]
+    pub rows: u32,
+    /// Number of columns, if this image is an atlas.
+    #[serde(default = "defaults::one", skip_serializing_if = "defaults::is_one")]
+    pub cols: u32,
+}
+
+mod defaults {
+    pub const fn one() -> u32 { 1 }
+    pub const fn is_one(x: &u32) -> bool { *x == 1 }
+}
+
+/// Convert a path in the manifest (separated by backslashes) to a [`PathBuf`].
+fn manifest_path(prefix: &str, path: &str) -> PathBuf {
+    prefix.split(['\\', '/'])
+        .chain(path.split(['\\', '/']))
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+impl ResourceManifest {
+    /// Parse the manifest from its XML source.
+    pub fn from_xml_str(src: &str) -> Result<ResourceManifest, XmlError> {
+        let mut parser = Parser::new(src);
+        let root = parser.open_tag()?
+            .ok_or_else(|| parser.error(XmlErrorKind::MissingTag("ResourceManifest")))?;
+        if root.name != "ResourceManifest" {
+            return Err(parser.error_at(root.pos, XmlErrorKind::UnexpectedTag {
+                found: root.name.to_string(),
+                expected: "<ResourceManifest>",
+            }));
+        }
+        let mut groups = Vec::new();
+        if !root.self_closing {
+            while let Some(tag) = parser.open_tag()? {
+                if tag.name == "Resources" {
+                    groups.push(parse_group(&mut parser, tag)?);
+                } else {
+                    tracing::warn!("ignored unknown tag <{}> in resource manifest", tag.name);
+                    parser.skip_element(tag)?;
+                }
+            }
+            parser.close_tag(root.name)?;
+        }
+        parser.expect_eof()?;
+        Ok(ResourceManifest { groups })
+    }
+
+    /// Iterate over all the resources in this manifest.
+    pub fn resources(&self) -> impl Iterator<Item = &Resource> {
+        self.groups.iter().flat_map(|group| group.resources.iter())
+    }
+
+    /// Build an index from image identifiers to image resources.
+    pub fn image_index(&self) -> HashMap<&str, &Resource> {
+        self.index(ResourceKind::Image)
+    }
+
+    /// Build an index from identifiers to resources of some kind.
+    pub fn index(&self, kind: ResourceKind) -> HashMap<&str, &Resource> {
+        self.resources()
+            .filter(|res| res.kind == kind)
+            .map(|res| (res.id.as_str(), res))
+            .collect()
+    }
+}
+
+fn parse_group(parser: &mut Parser, group: Tag) -> Result<ResourceGroup, XmlError> {
+    let id = group.attribute("id").unwrap_or_default();
+    let mut resources = Vec::new();
+    if group.self_closing { return Ok(ResourceGroup { id, resources }); }
+    let mut default_path = String::new();
+    let mut id_prefix = String::new();
+    while let Some(tag) = parser.open_tag()? {
+        let kind = match tag.name {
+            "SetDefaults" => {
+                default_path = tag.attribute("path").unwrap_or_default();
+                id_prefix = tag.attribute("idprefix").unwrap_or_default();
+                parser.skip_element(tag)?;
+                continue;
+            }
+            "Image" => ResourceKind::Image,
+            "Sound" => ResourceKind::Sound,
+            "Font" => ResourceKind::Font,
+            _ => {
+                tracing::warn!("ignored unknown resource <{}> in group '{id}'", tag.name);
+                parser.skip_element(tag)?;
+                continue;
+            }
+        };
+        let required = |name| tag.attribute(name)
+            .ok_or_else(|| parser.error_at(tag.pos, XmlErrorKind::MissingAttribute(name)));
+        let res_id = required("id")?;
+        let path = required("path")?;
+        let count = |name| tag.attribute(name).and_then(|n| n.parse().ok()).unwrap_or(1);
+        resources.push(Resource {
+            kind,
+            id: format!("{id_prefix}{res_id}"),
+            path: manifest_path(&default_path, &path),
+            alpha_image: tag.attribute("alphaimage").map(|p| manifest_path(&default_path, &p)),
+            alpha_grid: tag.attribute("alphagrid").map(|p| manifest_path(&default_path, &p)),
+            rows: count("rows"),
+            cols: count("cols"),
+        });
+        parser.skip_element(tag)?;
+    }
+    parser.close_tag(group.name)?;
+    Ok(ResourceGroup { id, resources })
+}
+
+/// Image file extensions tried by the game, in this order.
+pub const IMAGE_EXTENSIONS: [&str; 3] = ["png", "jpg", "gif"];
+
+/// Resolve image and font identifiers using a [`ResourceManifest`].
+#[derive(Debug)]
+pub struct ManifestResolver<'a> {
+    index: HashMap<&'a str, &'a Resource>,
+    paths: HashMap<PathBuf, &'a str>,
+    fonts: HashMap<&'a str, &'a Resource>,
+    font_paths: HashMap<PathBuf, &'a str>,
+    root: Option<&'a Path>,
+}
+
+impl<'a> ManifestResolver<'a> {
+    /// Create a resolver from the manifest. If the game directory `root` is provided, the image
+    /// extension is determined by probing the file system; otherwise, we assume `.png`. Fonts are
+    /// resolved to packed font assets (`*.font.bin`) next to their description scripts.
+    pub fn new(manifest: &'a ResourceManifest, root: Option<&'a Path>) -> Self {
+        // for files shared by several identifiers, the first one wins
+        let mut paths = HashMap::new();
+        let mut font_paths = HashMap::new();
+        for res in manifest.resources() {
+            match res.kind {
+                ResourceKind::Image => paths.entry(res.path.with_extension("")),
+                ResourceKind::Font => font_paths.entry(res.path.with_extension("font.bin")),
+                ResourceKind::Sound => continue,
+            }.or_insert(res.id.as_str());
+        }
+        let fonts = manifest.index(ResourceKind::Font);
+        ManifestResolver { index: manifest.image_index(), paths, fonts, font_paths, root }
+    }
+}
+
+impl<'a> ImageResolver for ManifestResolver<'a> {
+    fn resolve(&self, id: &str) -> Option<PathBuf> {
+        let res = self.index.get(id)?;
+        if res.rows != 1 || res.cols != 1 {
+            tracing::warn!(target: "pack", "image {id} is an atlas of {}x{} cells", res.rows, res.cols);
+        }
+        let ext = self.root.and_then(|root| IMAGE_EXTENSIONS.into_iter()
+            .find(|ext| root.join(&res.path).with_extension(ext).is_file()));
+        Some(res.path.with_extension(ext.unwrap_or("png")))
+    }
+    fn image_id(&self, path: &Path) -> Option<String> {
+        let ext = path.extension()?.to_str()?;
+        if !IMAGE_EXTENSIONS.contains(&ext) { return None; }
+        self.paths.get(&path.with_extension("")).map(|id| id.to_string())
+    }
+    fn resolve_font(&self, id: &str) -> Option<PathBuf> {
+        Some(self.fonts.get(id)?.path.with_extension("font.bin"))
+    }
+    fn font_id(&self, path: &Path) -> Option<String> {
+        self.font_paths.get(path).map(|id| id.to_string())
+    }
+}
@@ -16,14 +16,18 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-//! Binary streams for decoding `.reanim.compiled` files.
+//! Binary streams for decoding and encoding `.reanim.compiled` files.
 
 use std::fmt::{Display, Formatter};
-use std::io::Read;
+use std::io::{BufRead, Read, Write};
 use std::string::FromUtf8Error;
+use flate2::bufread::ZlibDecoder;
 use itertools::Itertools;
 use thiserror::Error;
 
+/// Derive macro for [`Decode`](trait@Decode), see [`reanim_decode_derive::Decode`].
+pub use reanim_decode_derive::Decode;
+
 /// Decoding errors.
 #[derive(Debug, Error)]
 pub enum DecodeError {
@@ -52,6 +56,14 @@ pub enum DecodeError {
     /// Superfluous bytes after decoding finished. EOF expected.
     #[error("input stream not exhausted, remaining bytes: {0:?}")]
     SuperfluousBytes(Box<[u8]>),
+    /// Some other error, with its location in the stream.
+    #[error("{error} at {location}")]
+    Located {
+        /// The actual error.
+        error: Box<DecodeError>,
+        /// Where the error happened.
+        location: Location,
+    },
 }
 
 use DecodeError::*;
@@ -69,6 +81,46 @@ impl From<FromUtf8Error> for DecodeError {
     }
 }
 
+/// A frame of decoding context, pushed by [`Decode`] implementations for diagnostics.
+#[derive(Debug, Clone)]
+pub enum Context {
+    /// Decoding a named item, e.g., `track 'anim_idle'`.
+    Named(&'static str, String),
+    /// Decoding an item in a series, e.g., `frame 17`.
+    Index(&'static str, usize),
+    /// Decoding a field, e.g., `field 'kx'`.
+    Field(&'static str),
+}
+
+impl Display for Context {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Context::Named(kind, name) => write!(f, "{kind} '{name}'"),
+            Context::Index(kind, k) => write!(f, "{kind} {k}"),
+            Context::Field(name) => write!(f, "field '{name}'"),
+        }
+    }
+}
+
+/// Location in a [`Stream`]: byte offset, together with the decoding context.
+#[derive(Debug, Clone)]
+pub struct Location {
+    /// Byte offset in the stream (after decompression, if any).
+    pub offset: u64,
+    /// Decoding context, outermost first.
+    pub context: Vec<Context>,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "offset {:#x}", self.offset)?;
+        if !self.context.is_empty() {
+            write!(f, " in {}", self.context.iter().format(" "))?;
+        }
+        Ok(())
+    }
+}
+
 /// [Result](std::result::Result) type specialised for [`DecodeError`].
 /// Can also be used as a substitute for [`std::result::Result`].
 pub type Result<T, E = DecodeError> = std::result::Result<T, E>;
@@ -85,6 +137,12 @@ pub trait PlainData: Sized {
     /// Length of the input slice is guaranteed to be `Self::SIZE_IN_BYTES`, but this information
     /// cannot be encoded in the type system (yet), due to limitations of `min_const_generics`.
     fn from_bytes(data: &[u8]) -> Option<Self>;
+    /// Encode to a byte sequence.
+    ///
+    /// # Note
+    /// Length of the output slice is guaranteed to be `Self::SIZE_IN_BYTES`, see also the note on
+    /// [`PlainData::from_bytes`].
+    fn to_bytes(&self, data: &mut [u8]);
 }
 
 macro_rules! impl_plain_data {
@@ -97,6 +155,9 @@ macro_rules! impl_plain_data {
                     let data: &[u8; Self::SIZE_IN_BYTES] = data.try_into().unwrap();
                     Some(<$type_name>::from_le_bytes(*data))
                 }
+                fn to_bytes(&self, data: &mut [u8]) {
+                    data.copy_from_slice(&self.to_le_bytes())
+                }
             }
         )+
     }
@@ -111,6 +172,9 @@ impl PlainData for Option<f32> {
         let n = f32::from_bytes(data)?;
         Some(if n <= -10000.0 { None } else { Some(n) })
     }
+    fn to_bytes(&self, data: &mut [u8]) {
+        self.unwrap_or(-10000.0).to_bytes(data)
+    }
 }
 
 /// 32bit magic sequence.
@@ -137,17 +201,62 @@ impl PlainData for Magic {
         let data: &[u8; 4] = data.try_into().unwrap();
         Some(Magic(*data))
     }
+    fn to_bytes(&self, data: &mut [u8]) {
+        data.copy_from_slice(&self.0)
+    }
 }
 
-/// Stream decoding API on top of [`Read`].
+/// Stream decoding API on top of [`Read`], keeping track of the location for diagnostics.
 pub trait Stream: Read {
+    /// Current byte offset in this stream.
+    fn offset(&self) -> u64;
+
+    /// Current decoding context, outermost first.
+    fn context(&self) -> &[Context];
+
+    /// Enter a new decoding context.
+    fn push_context(&mut self, context: Context);
+
+    /// Leave the innermost decoding context.
+    fn pop_context(&mut self);
+
+    /// Run some decoding action within a decoding context.
+    fn with_context<T>(&mut self, context: Context, action: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.push_context(context);
+        let result = action(self);
+        self.pop_context();
+        result
+    }
+
+    /// Attach the location (at the given offset) to an error, unless it already has one.
+    fn locate_at(&self, offset: u64, err: DecodeError) -> DecodeError {
+        match err {
+            Located { .. } => err,
+            err => Located {
+                error: Box::new(err),
+                location: Location { offset, context: self.context().to_vec() },
+            },
+        }
+    }
+
+    /// Attach the current location to an error, unless it already has one.
+    fn locate(&self, err: DecodeError) -> DecodeError {
+        self.locate_at(self.offset(), err)
+    }
+
     /// Decode a [`PlainData`] at the start of this stream.
     fn read_data<T: PlainData>(&mut self) -> Result<T> {
         tracing::trace!("reading plain data '{}'", T::TYPE_NAME);
+        let offset = self.offset();
         // to work around current limitations around min_const_generics
         let mut buffer = vec![0_u8; T::SIZE_IN_BYTES];
-        self.read_exact(&mut buffer).map_err(|err| IncompleteData(T::TYPE_NAME, err))?;
-        T::from_bytes(&buffer).ok_or(InvalidData(T::TYPE_NAME))
+        self.read_exact(&mut buffer).map_err(|err| self.locate_at(offset, IncompleteData(T::TYPE_NAME, err)))?;
+        T::from_bytes(&buffer).ok_or_else(|| self.locate_at(offset, InvalidData(T::TYPE_NAME)))
+    }
+
+    /// Decode a [`PlainData`] as a named field.
+    fn read_field<T: PlainData>(&mut self, name: &'static str) -> Result<T> {
+        self.with_context(Context::Field(name), |s| s.read_data::<T>())
     }
 
     /// Convenience function for `read::<Option<T>>`.
@@ -162,6 +271,12 @@ pub trait Stream: Read {
         std::iter::repeat_with(|| T::decode(self)).take(n).collect()
     }
 
+    /// Decode a series of `N` [`Decode`], with their indices (e.g., `frame 17`) as the context.
+    fn read_n_indexed<T: Decode<()>>(&mut self, kind: &'static str, n: usize) -> Result<Vec<T>> {
+        tracing::trace!("reading {n} consecutive elements ({kind})");
+        (0..n).map(|k| self.with_context(Context::Index(kind, k), T::decode)).collect()
+    }
+
     /// Decode a length `n`, and an array of `n` [`Decode`] at the start of this stream.
     fn read_array<T: Decode<()>>(&mut self) -> Result<Vec<T>> {
         let length = self.read_data::<u32>()?;
@@ -172,28 +287,39 @@ pub trait Stream: Read {
     fn read_string(&mut self) -> Result<String> {
         let length = self.read_data::<u32>()?;
         tracing::trace!("reading string of length {length}");
+        let offset = self.offset();
         let mut buffer = vec![0_u8; length as usize];
-        self.read_exact(&mut buffer).map_err(|err| IncompleteData("String", err))?;
-        String::from_utf8(buffer).map_err(Into::into)
+        self.read_exact(&mut buffer).map_err(|err| self.locate_at(offset, IncompleteData("String", err)))?;
+        String::from_utf8(buffer).map_err(|err| self.locate_at(offset, err.into()))
+    }
+
+    /// Decode a string as a named field.
+    fn read_string_field(&mut self, name: &'static str) -> Result<String> {
+        self.with_context(Context::Field(name), |s| s.read_string())
     }
 
     /// Decode and assert a 32bit magic.
     fn check_magic<M: Into<Magic>>(&mut self, magic: M) -> Result<()> {
         let magic = magic.into();
         tracing::trace!("checking magic {magic}");
+        let offset = self.offset();
         let val = self.read_data::<Magic>()?;
         if magic == val { Ok(()) } else {
-            Err(MagicMismatch {
+            Err(self.locate_at(offset, MagicMismatch {
                 real_bytes: val,
                 expected_magic: magic,
-            })
+            }))
         }
     }
 
     /// Drop some information we possibly do not understand yet.
+    ///
+    /// Non-zero padding is accepted (and logged), but not preserved: [`Sink::write_padding`] always
+    /// writes zeros, so such inputs do not round-trip byte for byte.
     fn drop_padding(&mut self, hint: &str, n: usize) -> Result<()> {
+        let offset = self.offset();
         let mut buffer = vec![0_u8; n];
-        self.read_exact(&mut buffer).map_err(|err| IncompleteData("padding", err))?;
+        self.read_exact(&mut buffer).map_err(|err| self.locate_at(offset, IncompleteData("padding", err)))?;
         if !buffer.iter().all(|x| *x == 0) {
             let buffer = buffer.iter().format(" ");
             tracing::info!("dropped {n} bytes of padding [{hint}]: {buffer:02X}");
@@ -204,7 +330,46 @@ pub trait Stream: Read {
     }
 }
 
-impl<S: Read + ?Sized> Stream for S {}
+/// A [`Stream`] on top of some [`Read`], counting the bytes read and keeping the context.
+#[derive(Debug)]
+pub struct StreamReader<R> {
+    inner: R,
+    offset: u64,
+    context: Vec<Context>,
+}
+
+impl<R> StreamReader<R> {
+    /// Start decoding from the beginning of a reader.
+    pub fn new(inner: R) -> Self {
+        StreamReader { inner, offset: 0, context: Vec::new() }
+    }
+
+    /// Get the underlying reader.
+    pub fn into_inner(self) -> R { self.inner }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for StreamReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> { self.inner.fill_buf() }
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.offset += amt as u64;
+    }
+}
+
+impl<R: Read> Stream for StreamReader<R> {
+    fn offset(&self) -> u64 { self.offset }
+    fn context(&self) -> &[Context] { &self.context }
+    fn push_context(&mut self, context: Context) { self.context.push(context) }
+    fn pop_context(&mut self) { self.context.pop(); }
+}
 
 /// Interface for named arguments in a [`Decode`].
 pub trait NamedArgs {
@@ -248,6 +413,19 @@ pub trait DecodeExt: Decode<()> {
 
 impl<T: Decode<()>> DecodeExt for T {}
 
+/// Decode a compiled definition (e.g., `.reanim.compiled`), which is optionally compressed.
+/// Performs decompression before decoding if necessary. Offsets in errors are relative to the
+/// decompressed data.
+pub fn decompress_and_decode<T, R>(s: &mut R) -> Result<T>
+    where T: Decode<()>, R: BufRead + ?Sized {
+    if let Ok([0xD4, 0xFE, 0xAD, 0xDE, ..]) = s.fill_buf() {
+        s.consume(8);
+        T::decode(&mut StreamReader::new(ZlibDecoder::new(s)))
+    } else {
+        T::decode(&mut StreamReader::new(s))
+    }
+}
+
 impl<T: PlainData> NamedArgs for T {
     type ArgsBuilder = NoArgs;
     fn args_builder() -> NoArgs { NoArgs }
@@ -256,3 +434,88 @@ impl<T: PlainData> NamedArgs for T {
 impl<T: PlainData> Decode<()> for T {
     fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<Self> { s.read_data::<T>() }
 }
+
+declare_no_args!(String);
+impl Decode<()> for String {
+    fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<String> { s.read_string() }
+}
+
+/// Optional values with some special encoding for [`None`], used by `#[decode(optional)]`.
+pub trait DecodeOptional: Sized {
+    /// Decode an optional value at current position in the [`Stream`].
+    fn decode_optional<S: Stream + ?Sized>(s: &mut S) -> Result<Option<Self>>;
+}
+
+impl<T> DecodeOptional for T where Option<T>: PlainData {
+    fn decode_optional<S: Stream + ?Sized>(s: &mut S) -> Result<Option<T>> { s.read_optional::<T>() }
+}
+
+/// Empty strings are treated as [`None`].
+impl DecodeOptional for String {
+    fn decode_optional<S: Stream + ?Sized>(s: &mut S) -> Result<Option<String>> {
+        let s = s.read_string()?;
+        Ok(if s.is_empty() { None } else { Some(s) })
+    }
+}
+
+/// Stream encoding API on top of [`Write`], the dual of [`Stream`].
+pub trait Sink: Write {
+    /// Encode a [`PlainData`] at the end of this sink.
+    fn write_data<T: PlainData>(&mut self, data: &T) -> std::io::Result<()> {
+        tracing::trace!("writing plain data '{}'", T::TYPE_NAME);
+        let mut buffer = vec![0_u8; T::SIZE_IN_BYTES];
+        data.to_bytes(&mut buffer);
+        self.write_all(&buffer)
+    }
+
+    /// Convenience function for `write::<Option<T>>`.
+    fn write_optional<T>(&mut self, data: &Option<T>) -> std::io::Result<()>
+        where Option<T>: PlainData {
+        self.write_data::<Option<T>>(data)
+    }
+
+    /// Encode a series of [`Encode`] at the end of this sink, without a length prefix.
+    fn write_n<T: Encode>(&mut self, xs: &[T]) -> std::io::Result<()> {
+        tracing::trace!("writing {} consecutive elements", xs.len());
+        xs.iter().try_for_each(|x| x.encode(self))
+    }
+
+    /// Encode a length `n`, and an array of `n` [`Encode`] at the end of this sink.
+    fn write_array<T: Encode>(&mut self, xs: &[T]) -> std::io::Result<()> {
+        self.write_data(&(xs.len() as u32))?;
+        self.write_n(xs)
+    }
+
+    /// Encode a length `n`, and then a string of length `n`.
+    fn write_string(&mut self, s: &str) -> std::io::Result<()> {
+        tracing::trace!("writing string of length {}", s.len());
+        self.write_data(&(s.len() as u32))?;
+        self.write_all(s.as_bytes())
+    }
+
+    /// Encode a 32bit magic.
+    fn write_magic<M: Into<Magic>>(&mut self, magic: M) -> std::io::Result<()> {
+        let magic = magic.into();
+        tracing::trace!("writing magic {magic}");
+        self.write_data(&magic)
+    }
+
+    /// Fill in some zero padding, the dual of [`Stream::drop_padding`]. The original contents of
+    /// the padding are lost in decoding, so zeros are written regardless.
+    fn write_padding(&mut self, hint: &str, n: usize) -> std::io::Result<()> {
+        tracing::trace!("writing {n} bytes of zero padding [{hint}]");
+        self.write_all(&vec![0_u8; n])
+    }
+}
+
+impl<S: Write + ?Sized> Sink for S {}
+
+/// Common entry for encoding binary data, the dual of [`Decode`].
+pub trait Encode {
+    /// Encode complex data at the end of the [`Sink`].
+    fn encode<S: Sink + ?Sized>(&self, s: &mut S) -> std::io::Result<()>;
+}
+
+impl<T: PlainData> Encode for T {
+    fn encode<S: Sink + ?Sized>(&self, s: &mut S) -> std::io::Result<()> { s.write_data(self) }
+}
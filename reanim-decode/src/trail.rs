@@ -0,0 +1,127 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Definition and decoding logic for compiled trail definitions (`.trail.compiled` files).
+//! See also [`particles`](crate::particles) for the layout of compiled definitions.
+
+use std::io::BufRead;
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+use libre_pvz_resources::cached::Cached;
+use libre_pvz_resources::param::FloatTrack;
+use libre_pvz_resources::trail as packed;
+use crate::particles::read_track_count;
+use crate::reanim::ImageResolver;
+use crate::stream::{decompress_and_decode, Context, Decode, Magic, Stream, Result};
+
+/// Trail definition in a `.trail` file.
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct TrailDefinition {
+    /// Image for the trail, e.g., `IMAGE_ICETRAIL`.
+    pub image: String,
+    /// Maximum number of points in the trail.
+    pub max_points: u32,
+    /// Minimum distance between two consecutive points.
+    pub min_point_distance: f32,
+    /// Flags for this trail. Only bit 0 (loops) is known.
+    pub flags: u32,
+    /// Lifetime of the trail, in centiseconds.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub trail_duration: FloatTrack,
+    /// Width along the trail.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub width_over_length: FloatTrack,
+    /// Width over the lifetime.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub width_over_time: FloatTrack,
+    /// Alpha along the trail.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub alpha_over_length: FloatTrack,
+    /// Alpha over the lifetime.
+    #[serde(default, skip_serializing_if = "FloatTrack::is_empty")]
+    pub alpha_over_time: FloatTrack,
+}
+
+/// Bit for looping trails in [`TrailDefinition::flags`].
+pub const TRAIL_FLAG_LOOPS: u32 = 1;
+
+impl TrailDefinition {
+    /// Decode a `.trail.compiled` trail definition.
+    /// Performs decompression before decoding if necessary.
+    pub fn decompress_and_decode<R: BufRead + ?Sized>(s: &mut R) -> Result<TrailDefinition> {
+        decompress_and_decode(s)
+    }
+
+    /// Pack this trail, resolving the image name using the given [`ImageResolver`].
+    pub fn pack_with<R: ImageResolver + ?Sized>(self, resolver: &R) -> packed::TrailDesc {
+        let image = resolver.resolve(&self.image).unwrap_or_else(|| {
+            tracing::error!(target: "pack", "exotic file name: {}", self.image);
+            PathBuf::from(&self.image)
+        });
+        if self.flags & !TRAIL_FLAG_LOOPS != 0 {
+            tracing::warn!(target: "pack", "dropped unknown trail flags: {:#X}", self.flags & !TRAIL_FLAG_LOOPS);
+        }
+        packed::TrailDesc {
+            image: Cached::from(image),
+            max_points: self.max_points,
+            min_point_distance: self.min_point_distance,
+            loops: self.flags & TRAIL_FLAG_LOOPS != 0,
+            duration: self.trail_duration,
+            width_over_length: self.width_over_length,
+            width_over_time: self.width_over_time,
+            alpha_over_length: self.alpha_over_length,
+            alpha_over_time: self.alpha_over_time,
+        }
+    }
+}
+
+/// Names of the float tracks, in the order they appear in the compiled definitions.
+const TRACK_FIELDS: [&str; 5] = [
+    "trail_duration", "width_over_length", "width_over_time", "alpha_over_length", "alpha_over_time",
+];
+
+declare_no_args!(TrailDefinition);
+impl Decode<()> for TrailDefinition {
+    fn decode_with<S: Stream + ?Sized>(s: &mut S, _args: ()) -> Result<TrailDefinition> {
+        tracing::debug!("decoding TrailDefinition (XML root node)");
+        // hash of the definition schema, not checked here
+        let schema = s.read_data::<Magic>()?;
+        tracing::debug!("definition schema hash: {schema}");
+        s.drop_padding("image", 4)?;
+        let max_points = s.read_field::<u32>("max_points")?;
+        let min_point_distance = s.read_field::<f32>("min_point_distance")?;
+        let flags = s.read_field::<u32>("flags")?;
+        let mut counts = [0; 5];
+        for (count, field) in std::iter::zip(&mut counts, TRACK_FIELDS) {
+            *count = s.with_context(Context::Field(field), read_track_count)?;
+        }
+        let image = s.read_string_field("image")?;
+        let [trail_duration, width_over_length, width_over_time, alpha_over_length, alpha_over_time] =
+            std::array::from_fn(|k| {
+                s.with_context(Context::Field(TRACK_FIELDS[k]), |s| FloatTrack::decode_with(s, counts[k]))
+            });
+        Ok(TrailDefinition {
+            image, max_points, min_point_distance, flags,
+            trail_duration: trail_duration?,
+            width_over_length: width_over_length?,
+            width_over_time: width_over_time?,
+            alpha_over_length: alpha_over_length?,
+            alpha_over_time: alpha_over_time?,
+        })
+    }
+}
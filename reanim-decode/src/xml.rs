@@ -19,7 +19,8 @@
 //! Original XML format for `.reanim` files.
 
 use std::fmt::{Display, Formatter};
-use crate::reanim::{Animation, Elements, Track, Transform};
+use thiserror::Error;
+use crate::reanim::{Animation, Elements, Frame, Track, Transform};
 
 /// Display in XML format.
 pub trait DisplayXml {
@@ -76,7 +77,23 @@ macro_rules! impl_display_xml {
 impl_display_xml! {
     u8, u16, u32, u64, u128,
     i8, i16, i32, i64, i128,
-    f32, f64, String,
+    f32, f64,
+}
+
+impl DisplayXml for String {
+    fn fmt_xml(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut rest = self.as_str();
+        while let Some(k) = rest.find(['<', '>', '&']) {
+            f.write_str(&rest[..k])?;
+            f.write_str(match rest.as_bytes()[k] {
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                _ => "&amp;",
+            })?;
+            rest = &rest[k + 1..];
+        }
+        f.write_str(rest)
+    }
 }
 
 impl DisplayXml for Animation {
@@ -127,3 +144,405 @@ impl DisplayXml for Elements {
         Ok(())
     }
 }
+
+/// Errors when parsing the XML format, with location information.
+#[derive(Debug, Error)]
+#[error("{line}:{column}: {kind}")]
+pub struct XmlError {
+    /// Line number (1-based) at which the error is detected.
+    pub line: usize,
+    /// Column number (1-based, in characters) at which the error is detected.
+    pub column: usize,
+    /// What went wrong.
+    pub kind: XmlErrorKind,
+}
+
+/// Kinds of errors when parsing the XML format.
+#[derive(Debug, Error)]
+pub enum XmlErrorKind {
+    /// Unexpected end of input.
+    #[error("unexpected end of input, expecting {0}")]
+    UnexpectedEof(&'static str),
+    /// Unexpected character.
+    #[error("unexpected character '{found}', expecting {expected}")]
+    UnexpectedChar {
+        /// The character we found in the input.
+        found: char,
+        /// Description of what we expect here.
+        expected: &'static str,
+    },
+    /// Unexpected tag.
+    #[error("unexpected tag <{found}>, expecting {expected}")]
+    UnexpectedTag {
+        /// The tag we found in the input.
+        found: String,
+        /// Description of what we expect here.
+        expected: &'static str,
+    },
+    /// Closing tag does not match the opening one.
+    #[error("mismatched closing tag: expecting </{expected}>, found </{found}>")]
+    MismatchedTag {
+        /// The opening tag.
+        expected: String,
+        /// The closing tag we found in the input.
+        found: String,
+    },
+    /// Some field is specified more than once.
+    #[error("duplicate tag <{0}>")]
+    DuplicateTag(String),
+    /// Some required field is missing.
+    #[error("missing tag <{0}>")]
+    MissingTag(&'static str),
+    /// Some required attribute is missing.
+    #[error("missing attribute '{0}'")]
+    MissingAttribute(&'static str),
+    /// Invalid float number.
+    #[error("invalid number '{0}'")]
+    InvalidNumber(String),
+    /// Unknown or malformed entity reference.
+    #[error("unknown entity '&{0};'")]
+    UnknownEntity(String),
+}
+
+use XmlErrorKind::*;
+
+/// Parse from the XML format.
+pub trait FromXml: Sized {
+    /// Parse from an XML string. The whole input should be consumed.
+    fn from_xml_str(src: &str) -> Result<Self, XmlError>;
+}
+
+impl FromXml for Animation {
+    fn from_xml_str(src: &str) -> Result<Animation, XmlError> {
+        let mut parser = Parser::new(src);
+        let mut fps = None;
+        let mut tracks = Vec::new();
+        while let Some(tag) = parser.open_tag()? {
+            match tag.name {
+                "fps" => {
+                    let value = parser.float_content(tag)?;
+                    parser.set_once(&mut fps, value, tag)?;
+                }
+                "track" => tracks.push(parse_track(&mut parser, tag)?),
+                _ => return Err(parser.error_at(tag.pos, UnexpectedTag {
+                    found: tag.name.to_string(),
+                    expected: "<fps> or <track>",
+                })),
+            }
+        }
+        parser.expect_eof()?;
+        let fps = fps.ok_or_else(|| parser.error(MissingTag("fps")))?;
+        Ok(Animation { fps, tracks: tracks.into_boxed_slice() })
+    }
+}
+
+fn parse_track(parser: &mut Parser, track: Tag) -> Result<Track, XmlError> {
+    let mut name = None;
+    let mut frames = Vec::new();
+    if !track.self_closing {
+        while let Some(tag) = parser.open_tag()? {
+            match tag.name {
+                "name" => {
+                    let value = parser.text_content(tag)?;
+                    parser.set_once(&mut name, value, tag)?;
+                }
+                "t" => frames.push(parse_frame(parser, tag)?),
+                _ => return Err(parser.error_at(tag.pos, UnexpectedTag {
+                    found: tag.name.to_string(),
+                    expected: "<name> or <t>",
+                })),
+            }
+        }
+        parser.close_tag(track.name)?;
+    }
+    let name = name.ok_or_else(|| parser.error_at(track.pos, MissingTag("name")))?;
+    Ok(Track { name, frames: frames.into_boxed_slice() })
+}
+
+fn parse_frame(parser: &mut Parser, frame: Tag) -> Result<Frame, XmlError> {
+    let mut transform = Transform { x: None, y: None, kx: None, ky: None, sx: None, sy: None, f: None, a: None };
+    let mut elements = Elements { image: None, font: None, text: None };
+    if frame.self_closing { return Ok(Frame { transform, elements }); }
+    while let Some(tag) = parser.open_tag()? {
+        let field = match tag.name {
+            "x" => &mut transform.x,
+            "y" => &mut transform.y,
+            "kx" => &mut transform.kx,
+            "ky" => &mut transform.ky,
+            "sx" => &mut transform.sx,
+            "sy" => &mut transform.sy,
+            "f" => &mut transform.f,
+            "a" => &mut transform.a,
+            _ => {
+                let field = match tag.name {
+                    "i" => &mut elements.image,
+                    "font" => &mut elements.font,
+                    "text" => &mut elements.text,
+                    _ => return Err(parser.error_at(tag.pos, UnexpectedTag {
+                        found: tag.name.to_string(),
+                        expected: "one of <x>, <y>, <kx>, <ky>, <sx>, <sy>, <f>, <a>, <i>, <font>, <text>",
+                    })),
+                };
+                // empty strings are treated as absent, as in the compiled format
+                let value = parser.text_content(tag)?;
+                if !value.is_empty() { parser.set_once(field, value, tag)?; }
+                continue;
+            }
+        };
+        let value = parser.float_content(tag)?;
+        parser.set_once(field, value, tag)?;
+    }
+    parser.close_tag(frame.name)?;
+    Ok(Frame { transform, elements })
+}
+
+#[derive(Copy, Clone)]
+pub(crate) struct Tag<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) pos: usize,
+    pub(crate) self_closing: bool,
+    /// Raw attribute list, already validated when parsing the tag.
+    attributes: &'a str,
+}
+
+impl<'a> Tag<'a> {
+    /// Iterate over the attributes (in source order), with entities decoded.
+    pub(crate) fn attributes(self) -> impl Iterator<Item = (&'a str, String)> {
+        let mut rest = self.attributes;
+        std::iter::from_fn(move || {
+            rest = rest.trim_start();
+            let eq = rest.find('=')?;
+            let name = rest[..eq].trim_end();
+            let value = rest[eq + 1..].trim_start();
+            let quote = value.chars().next()?;
+            let end = value[1..].find(quote)? + 1;
+            let decoded = decode_text(&value[1..end]).unwrap_or_default();
+            rest = &value[end + 1..];
+            Some((name, decoded))
+        })
+    }
+
+    /// Get the value of some attribute.
+    pub(crate) fn attribute(self, name: &str) -> Option<String> {
+        self.attributes().find_map(|(key, value)| (key == name).then_some(value))
+    }
+}
+
+pub(crate) struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(src: &'a str) -> Self {
+        // skip the UTF-8 byte order mark, if any
+        Parser { src, pos: if src.starts_with('\u{FEFF}') { '\u{FEFF}'.len_utf8() } else { 0 } }
+    }
+
+    fn rest(&self) -> &'a str { &self.src[self.pos..] }
+
+    pub(crate) fn error_at(&self, pos: usize, kind: XmlErrorKind) -> XmlError {
+        let prefix = &self.src[..pos];
+        let line = prefix.matches('\n').count() + 1;
+        let line_start = prefix.rfind('\n').map_or(0, |k| k + 1);
+        let column = prefix[line_start..].chars().count() + 1;
+        XmlError { line, column, kind }
+    }
+
+    pub(crate) fn error(&self, kind: XmlErrorKind) -> XmlError { self.error_at(self.pos, kind) }
+
+    fn unexpected(&self, expected: &'static str) -> XmlError {
+        match self.rest().chars().next() {
+            None => self.error(UnexpectedEof(expected)),
+            Some(found) => self.error(UnexpectedChar { found, expected }),
+        }
+    }
+
+    pub(crate) fn set_once<T>(&self, slot: &mut Option<T>, value: T, tag: Tag) -> Result<(), XmlError> {
+        if slot.is_some() { return Err(self.error_at(tag.pos, DuplicateTag(tag.name.to_string()))); }
+        *slot = Some(value);
+        Ok(())
+    }
+
+    /// Skip whitespaces, comments, and processing instructions (e.g., `<?xml ...?>`).
+    fn skip_trivia(&mut self) -> Result<(), XmlError> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            let (open, close, expected) = if trimmed.starts_with("<!--") {
+                ("<!--", "-->", "end of comment '-->'")
+            } else if trimmed.starts_with("<?") {
+                ("<?", "?>", "end of processing instruction '?>'")
+            } else {
+                return Ok(());
+            };
+            match trimmed[open.len()..].find(close) {
+                Some(k) => self.pos += open.len() + k + close.len(),
+                None => {
+                    self.pos = self.src.len();
+                    return Err(self.error(UnexpectedEof(expected)));
+                }
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn name(&mut self) -> Result<&'a str, XmlError> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || "_-.:".contains(c))).unwrap_or(rest.len());
+        if len == 0 { return Err(self.unexpected("a tag name")); }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    fn expect_char(&mut self, c: char, expected: &'static str) -> Result<(), XmlError> {
+        if self.rest().starts_with(c) {
+            self.pos += c.len_utf8();
+            Ok(())
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    /// Parse an opening tag, or return [`None`] for a closing tag or end of input.
+    pub(crate) fn open_tag(&mut self) -> Result<Option<Tag<'a>>, XmlError> {
+        self.skip_trivia()?;
+        let rest = self.rest();
+        if rest.is_empty() || rest.starts_with("</") { return Ok(None); }
+        let pos = self.pos;
+        self.expect_char('<', "an opening tag")?;
+        let name = self.name()?;
+        let attributes_start = self.pos;
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.is_empty() || rest.starts_with(['/', '>']) { break; }
+            self.name()?;
+            self.skip_whitespace();
+            self.expect_char('=', "'='")?;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => return Err(self.unexpected("a quoted attribute value")),
+            };
+            self.pos += 1;
+            let end = self.rest().find(quote).ok_or_else(|| self.error(UnexpectedEof("end of attribute value")))?;
+            decode_text(&self.rest()[..end]).map_err(|entity| self.error(UnknownEntity(entity)))?;
+            self.pos += end + 1;
+        }
+        let attributes = &self.src[attributes_start..self.pos];
+        let self_closing = self.rest().starts_with('/');
+        if self_closing { self.pos += 1; }
+        self.expect_char('>', "'>'")?;
+        Ok(Some(Tag { name, pos, self_closing, attributes }))
+    }
+
+    /// Skip an element, including all its children, and its closing tag.
+    pub(crate) fn skip_element(&mut self, tag: Tag) -> Result<(), XmlError> {
+        if tag.self_closing { return Ok(()); }
+        loop {
+            let rest = self.rest();
+            self.pos += rest.find('<').unwrap_or(rest.len());
+            match self.open_tag()? {
+                Some(child) => self.skip_element(child)?,
+                None => break,
+            }
+        }
+        self.close_tag(tag.name)
+    }
+
+    pub(crate) fn close_tag(&mut self, expected: &str) -> Result<(), XmlError> {
+        self.skip_trivia()?;
+        let pos = self.pos;
+        if !self.rest().starts_with("</") {
+            return Err(self.unexpected("a closing tag"));
+        }
+        self.pos += 2;
+        let name = self.name()?;
+        if name != expected {
+            return Err(self.error_at(pos, MismatchedTag {
+                expected: expected.to_string(),
+                found: name.to_string(),
+            }));
+        }
+        self.skip_trivia()?;
+        self.expect_char('>', "'>'")
+    }
+
+    pub(crate) fn expect_eof(&mut self) -> Result<(), XmlError> {
+        self.skip_trivia()?;
+        if self.rest().is_empty() { Ok(()) } else { Err(self.unexpected("end of input")) }
+    }
+
+    /// Parse the text content of some tag, and its closing tag.
+    pub(crate) fn text_content(&mut self, tag: Tag) -> Result<String, XmlError> {
+        let mut text = String::new();
+        if tag.self_closing { return Ok(text); }
+        loop {
+            let rest = self.rest();
+            let k = rest.find(['<', '&']).unwrap_or(rest.len());
+            text.push_str(&rest[..k]);
+            self.pos += k;
+            let rest = self.rest();
+            if rest.starts_with("<!--") {
+                self.skip_trivia()?;
+            } else if rest.starts_with('&') {
+                let end = rest.find(';').ok_or_else(|| self.error(UnexpectedEof("';'")))?;
+                let entity = &rest[1..end];
+                text.push(decode_entity(entity).ok_or_else(|| self.error(UnknownEntity(entity.to_string())))?);
+                self.pos += end + 1;
+            } else {
+                break;
+            }
+        }
+        self.close_tag(tag.name)?;
+        Ok(text)
+    }
+
+    /// Parse the content of some tag as a float number, and its closing tag.
+    pub(crate) fn float_content(&mut self, tag: Tag) -> Result<f32, XmlError> {
+        self.skip_trivia()?;
+        let pos = self.pos;
+        let text = self.text_content(tag)?;
+        let text = text.trim();
+        text.parse().map_err(|_| self.error_at(pos, InvalidNumber(text.to_string())))
+    }
+}
+
+/// Decode all the entity references in some text, or report the first invalid entity.
+fn decode_text(raw: &str) -> Result<String, String> {
+    let mut text = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(k) = rest.find('&') {
+        text.push_str(&rest[..k]);
+        rest = &rest[k + 1..];
+        let end = rest.find(';').ok_or_else(|| rest.to_string())?;
+        text.push(decode_entity(&rest[..end]).ok_or_else(|| rest[..end].to_string())?);
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    Ok(text)
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    Some(match entity {
+        "lt" => '<',
+        "gt" => '>',
+        "amp" => '&',
+        "apos" => '\'',
+        "quot" => '"',
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix('x') {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    })
+}
@@ -0,0 +1,59 @@
+/*
+ * reanim-decode: decoder for PvZ reanim files.
+ * Copyright (c) 2022  Ruifeng Xie
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Round trip for `.reanim.compiled` files: decode, encode again, and compare the bytes.
+//!
+//! The fixture contains zero padding only, see [`Sink::write_padding`].
+//!
+//! [`Sink::write_padding`]: reanim_decode::stream::Sink::write_padding
+
+use std::io::Read;
+use flate2::read::ZlibDecoder;
+use reanim_decode::reanim::Animation;
+
+/// Compressed, as in the original game.
+const SIMPLE: &[u8] = include_bytes!("data/simple.reanim.compiled");
+
+fn encode(anim: &Animation, compress: bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    anim.encode_and_compress(&mut bytes, compress).unwrap();
+    bytes
+}
+
+fn decompress(bytes: &[u8]) -> Vec<u8> {
+    let (header, payload) = bytes.split_at(8);
+    assert_eq!(header[..4], [0xD4, 0xFE, 0xAD, 0xDE]);
+    let mut result = Vec::new();
+    ZlibDecoder::new(payload).read_to_end(&mut result).unwrap();
+    assert_eq!(header[4..], (result.len() as u32).to_le_bytes());
+    result
+}
+
+#[test]
+fn compressed_round_trip() {
+    let anim = Animation::decompress_and_decode(&mut &*SIMPLE).unwrap();
+    assert_eq!(anim.tracks.len(), 3);
+    assert_eq!(encode(&anim, true), SIMPLE);
+}
+
+#[test]
+fn uncompressed_round_trip() {
+    let uncompressed = decompress(SIMPLE);
+    let anim = Animation::decompress_and_decode(&mut &*uncompressed).unwrap();
+    assert_eq!(encode(&anim, false), uncompressed);
+}